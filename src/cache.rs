@@ -0,0 +1,434 @@
+// Cache.rs handles storing proxied responses on the disk, so that they can be served without contacting the upstream server.
+
+// Cache sizes are in bytes, and will never be large enough for this to be an issue.
+#![allow(clippy::cast_possible_truncation)]
+// Dates before 1970 can't be used as an expiry time, so this can be ignored.
+#![allow(clippy::cast_sign_loss)]
+
+extern crate actix;
+extern crate actix_web;
+extern crate futures;
+extern crate bytes;
+extern crate chrono;
+extern crate toml;
+
+use futures::{Async, Future, Poll, Stream};
+use bytes::{Bytes, BytesMut};
+use std::{collections::{HashMap, HashSet, hash_map::DefaultHasher}, fs, hash::{Hash, Hasher}, sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}}, time::{SystemTime, UNIX_EPOCH}};
+use self::actix::Arbiter;
+use self::chrono::DateTime;
+use actix_web::{client::Client, http::{header, header::{HeaderMap, HeaderName, HeaderValue}, Method, ContentEncoding, StatusCode}, middleware::BodyEncoding, HttpResponse};
+
+// Meta contains everything about a cached response except for the body. It is stored next to the body, as TOML.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Meta {
+	url: String,
+	status: u16,
+	stored: u64,
+	fresh: u64,
+	swr: u64,
+	sie: u64,
+	vary: Vec<String>,
+	headers: Vec<(String, String)>,
+}
+
+// A cached response, along with its body.
+pub struct Entry {
+	key: String,
+	meta: Meta,
+	body: Bytes,
+}
+
+impl Entry {
+	fn age(&self) -> u64 {
+		now().saturating_sub(self.meta.stored)
+	}
+
+	// Check if the entry can be served without contacting the upstream server.
+	pub fn is_fresh(&self) -> bool {
+		self.age() < self.meta.fresh
+	}
+
+	// Check if the entry can be served while it is revalidated in the background.
+	pub fn stale_while_revalidate(&self) -> bool {
+		self.age() < self.meta.fresh + self.meta.swr
+	}
+
+	// Check if the entry can be served when the upstream server is failing.
+	pub fn stale_if_error(&self) -> bool {
+		self.age() < self.meta.fresh + self.meta.sie
+	}
+
+	// Turn the entry into a response, with the X-Cache header set to the provided status.
+	pub fn response(&self, status: &str) -> HttpResponse {
+		let mut resp = HttpResponse::Ok();
+		resp.status(StatusCode::from_u16(self.meta.status).unwrap_or(StatusCode::OK));
+		for (key, value) in &self.meta.headers {
+			if key == "content-encoding" {
+				resp.encoding(ContentEncoding::Identity);
+			}
+			resp.header(key.as_str(), value.as_str());
+		}
+
+		resp.header(header::AGE, self.age().to_string())
+			.header("X-Cache", status)
+			.body(self.body.to_owned())
+	}
+}
+
+// Index tracks the size and last use of every cached response, so that the least recently used responses can be removed when the cache gets too large.
+struct Index {
+	total: u64,
+	entries: HashMap<String, (u64, u64)>,
+}
+
+// Temporary files are numbered, so that responses stored at the same time don't overwrite each other's temporary files.
+static TEMP_ID: AtomicUsize = AtomicUsize::new(0);
+
+pub struct Cache {
+	folder: String,
+	max_size: u64,
+	index: Mutex<Option<Index>>,
+	revalidating: Mutex<HashSet<String>>,
+}
+
+impl Cache {
+	pub fn new(folder: String, max_size: u64) -> Self {
+		Self { folder, max_size, index: Mutex::new(None), revalidating: Mutex::new(HashSet::new()) }
+	}
+
+	// The largest response that will be cached. Responses this size are small enough to be kept in memory while they are being stored.
+	pub fn max_object(&self) -> u64 {
+		self.max_size / 16
+	}
+
+	fn path(&self, key: &str, ext: &str) -> String {
+		[&self.folder, "/", key, ext].concat()
+	}
+
+	// Run a function on the index, loading it from the disk if it hasn't been loaded yet.
+	fn with_index<F: FnOnce(&mut Index)>(&self, f: F) {
+		let mut lock = self.index.lock().unwrap_or_else(|e| e.into_inner());
+		if lock.is_none() {
+			let mut index = Index { total: 0, entries: HashMap::new() };
+			if let Ok(dir) = fs::read_dir(&self.folder) {
+				for file in dir.filter_map(Result::ok) {
+					let path = file.path();
+					if path.extension().and_then(|e| e.to_str()) != Some("body") {
+						continue
+					}
+					if let (Some(key), Ok(m)) = (path.file_stem().and_then(|k| k.to_str()), file.metadata()) {
+						let used = m.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map_or(0, |d| d.as_secs());
+						index.total += m.len();
+						index.entries.insert(key.to_owned(), (m.len(), used));
+					}
+				}
+			}
+			*lock = Some(index);
+		}
+
+		if let Some(index) = lock.as_mut() {
+			f(index)
+		}
+	}
+
+	// Write a file in the cache folder by writing a temporary file and renaming it, so that partially written files are never read.
+	fn write(&self, key: &str, ext: &str, data: &[u8]) -> bool {
+		let path = self.path(key, ext);
+		let temp = [&path, ".", &TEMP_ID.fetch_add(1, Ordering::Relaxed).to_string(), ".tmp"].concat();
+		if fs::write(&temp, data).and_then(|_| fs::rename(&temp, &path)).is_err() {
+			let _ = fs::remove_file(&temp);
+			return false
+		}
+		true
+	}
+
+	fn read_meta(&self, key: &str, url: &str) -> Option<Meta> {
+		let meta: Meta = toml::from_str(&fs::read_to_string(self.path(key, ".toml")).ok()?).ok()?;
+		if meta.url != url {
+			return None
+		}
+		Some(meta)
+	}
+
+	// Find a cached response for a URL, taking the Vary header of the cached response into account.
+	pub fn lookup(&self, url: &str, headers: &HeaderMap) -> Option<Entry> {
+		let mut key = hash(url);
+		let mut meta = self.read_meta(&key, url)?;
+		if !meta.vary.is_empty() {
+			key = variant_key(url, &meta.vary, headers);
+			meta = self.read_meta(&key, url)?;
+		}
+
+		let body = Bytes::from(fs::read(self.path(&key, ".body")).ok()?);
+		self.with_index(|index| {
+			if let Some(e) = index.entries.get_mut(&key) {
+				e.1 = now();
+			}
+		});
+		Some(Entry { key, meta, body })
+	}
+
+	// Store a response in the cache, removing the least recently used responses if the cache becomes too large.
+	fn store(&self, url: &str, req_headers: &HeaderMap, mut meta: Meta, body: &[u8]) {
+		let mut key = hash(url);
+		if !meta.vary.is_empty() {
+			// The primary entry only points to the headers which select a variant.
+			let pointer = Meta { status: 0, headers: Vec::new(), ..meta.to_owned() };
+			if let Ok(data) = toml::to_string(&pointer) {
+				self.write(&key, ".toml", data.as_bytes());
+			}
+			key = variant_key(url, &meta.vary, req_headers);
+		}
+
+		meta.stored = now();
+		if fs::create_dir_all(&self.folder).is_err() || !self.write(&key, ".body", body) {
+			println!("[Warn]: Unable to write to the cache folder!");
+			return
+		}
+		if let Ok(data) = toml::to_string(&meta) {
+			self.write(&key, ".toml", data.as_bytes());
+		}
+
+		let size = body.len() as u64;
+		let max_size = self.max_size;
+		let mut evicted = Vec::new();
+		self.with_index(|index| {
+			if let Some((old, _)) = index.entries.insert(key.to_owned(), (size, now())) {
+				index.total -= old;
+			}
+			index.total += size;
+
+			while index.total > max_size {
+				let oldest = match index.entries.iter().min_by_key(|(_, e)| e.1) {
+					Some((k, _)) => k.to_owned(),
+					None => break,
+				};
+				if let Some((s, _)) = index.entries.remove(&oldest) {
+					index.total -= s;
+				}
+				evicted.push(oldest);
+			}
+		});
+
+		for key in evicted {
+			let _ = fs::remove_file(self.path(&key, ".body"));
+			let _ = fs::remove_file(self.path(&key, ".toml"));
+		}
+	}
+
+	// Remove a cached response, usually because the upstream server says it can no longer be used.
+	pub fn remove(&self, entry: &Entry) {
+		let _ = fs::remove_file(self.path(&entry.key, ".body"));
+		let _ = fs::remove_file(self.path(&entry.key, ".toml"));
+		self.with_index(|index| {
+			if let Some((s, _)) = index.entries.remove(&entry.key) {
+				index.total -= s;
+			}
+		});
+	}
+}
+
+// Build the URL a response is cached under. The public URL of the location is included, as the response's headers are rewritten to point to it, so a response can't be shared between hosts.
+pub fn cache_url(location: &str, base: &str, path: &str) -> String {
+	[location, " ", base, " ", path].concat()
+}
+
+// Check if a request is allowed to be answered from the cache.
+pub fn request_cacheable(method: &Method, headers: &HeaderMap) -> bool {
+	if (*method != Method::GET && *method != Method::HEAD) || headers.contains_key(header::AUTHORIZATION) {
+		return false
+	}
+
+	let cc = headers.get(header::CACHE_CONTROL).and_then(|h| h.to_str().ok()).unwrap_or("");
+	!cc.contains("no-store") && !cc.contains("no-cache")
+}
+
+/* Create the metadata for a response, if it is allowed to be cached.
+Only responses which explicitly allow caching through Cache-Control or Expires are cached. */
+pub fn response_meta(url: &str, status: StatusCode, headers: &HeaderMap) -> Option<Meta> {
+	match status.as_u16() {
+		200 | 203 | 300 | 301 | 404 | 410 => (),
+		_ => return None,
+	}
+	if headers.contains_key(header::SET_COOKIE) {
+		return None
+	}
+
+	let vary: Vec<String> = headers.get_all(header::VARY).filter_map(|h| h.to_str().ok())
+		.flat_map(|h| h.split(',')).map(|h| h.trim().to_lowercase()).filter(|h| !h.is_empty()).collect();
+	if vary.iter().any(|h| h == "*") {
+		return None
+	}
+
+	let (mut fresh, mut swr, mut sie, mut shared) = (None, 0, 0, false);
+	for directive in headers.get_all(header::CACHE_CONTROL).filter_map(|h| h.to_str().ok()).flat_map(|h| h.split(',')) {
+		let mut parts = directive.trim().splitn(2, '=');
+		let name = parts.next().unwrap_or("").to_lowercase();
+		let value = parts.next().and_then(|v| v.trim_matches('"').parse::<u64>().ok());
+		match (name.as_str(), value) {
+			("no-store", _) | ("no-cache", _) | ("private", _) => return None,
+			("s-maxage", Some(v)) => {fresh = Some(v); shared = true},
+			("max-age", Some(v)) if !shared => fresh = Some(v),
+			("stale-while-revalidate", Some(v)) => swr = v,
+			("stale-if-error", Some(v)) => sie = v,
+			_ => (),
+		}
+	}
+
+	// If there isn't a max-age, fall back to the Expires header. The Date header is used as the current time, to avoid issues with clock differences.
+	if fresh.is_none() {
+		let expires = parse_date(headers.get(header::EXPIRES))?;
+		let date = parse_date(headers.get(header::DATE)).unwrap_or_else(now);
+		fresh = Some(expires.saturating_sub(date));
+	}
+
+	let fresh = fresh.unwrap_or(0);
+	if fresh == 0 && swr == 0 && sie == 0 {
+		return None
+	}
+
+	let headers = headers.iter().filter(|(k, _)| match k.as_str() {
+		"connection" | "proxy-connection" | "keep-alive" | "proxy-authenticate" | "transfer-encoding" | "upgrade" | "age" | "x-cache" => false,
+		_ => true,
+	}).filter_map(|(k, v)| Some((k.as_str().to_owned(), v.to_str().ok()?.to_owned()))).collect();
+
+	Some(Meta { url: url.to_owned(), status: status.as_u16(), stored: 0, fresh, swr, sie, vary, headers })
+}
+
+/* Refresh a cached response in the background, while the stale response is being served. Only one refresh is done for each cached response at a time.
+The headers are the same headers that would have been sent to the upstream server, and req_headers are the client's headers, which select the variant the response is stored as. */
+pub fn revalidate(client: &Client, cache: Arc<Cache>, entry: &Entry, upstream_url: &str, headers: HeaderMap, req_headers: HeaderMap) {
+	if !cache.revalidating.lock().unwrap_or_else(|e| e.into_inner()).insert(entry.key.to_owned()) {
+		return
+	}
+
+	let mut req = client.get(upstream_url).no_decompress();
+	for (key, value) in headers.iter() {
+		req = req.header(key.to_owned(), value.to_owned());
+	}
+
+	let (url, key, done) = (entry.meta.url.to_owned(), entry.key.to_owned(), cache.to_owned());
+	let max = cache.max_object() as usize;
+	Arbiter::spawn(req.send().map_err(|_| ()).and_then(move |mut resp| {
+		let meta = response_meta(&url, resp.status(), resp.headers());
+		resp.body().limit(max).map_err(|_| ()).map(move |body| {
+			if let Some(meta) = meta {
+				cache.store(&url, &req_headers, meta, &body);
+			}
+		})
+	}).then(move |_| -> Result<(), ()> {
+		done.revalidating.lock().unwrap_or_else(|e| e.into_inner()).remove(&key);
+		Ok(())
+	}));
+}
+
+// Tee passes a response body through, while saving a copy of it. Once the body has been fully read, the copy is stored in the cache.
+pub struct Tee<S> {
+	stream: S,
+	buf: Option<BytesMut>,
+	store: Option<(Arc<Cache>, String, HeaderMap, Meta)>,
+}
+
+impl<S> Tee<S> {
+	// If store is None, the body is passed through without being cached.
+	pub fn new(stream: S, store: Option<(Arc<Cache>, String, HeaderMap, Meta)>) -> Self {
+		Self {
+			stream,
+			buf: store.as_ref().map(|_| BytesMut::new()),
+			store,
+		}
+	}
+}
+
+impl<S: Stream<Item=Bytes>> Stream for Tee<S> {
+	type Item = Bytes;
+	type Error = S::Error;
+
+	fn poll(&mut self) -> Poll<Option<Bytes>, S::Error> {
+		match self.stream.poll()? {
+			Async::Ready(Some(chunk)) => {
+				let max = self.store.as_ref().map_or(0, |s| s.0.max_object());
+				if let Some(buf) = self.buf.as_mut() {
+					buf.extend_from_slice(&chunk);
+				}
+				if self.buf.as_ref().map_or(false, |b| b.len() as u64 > max) {
+					self.buf = None;
+				}
+				Ok(Async::Ready(Some(chunk)))
+			},
+			Async::Ready(None) => {
+				if let (Some(buf), Some((cache, url, headers, meta))) = (self.buf.take(), self.store.take()) {
+					cache.store(&url, &headers, meta, &buf);
+				}
+				Ok(Async::Ready(None))
+			},
+			Async::NotReady => Ok(Async::NotReady),
+		}
+	}
+}
+
+fn now() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+fn parse_date(value: Option<&HeaderValue>) -> Option<u64> {
+	let date = DateTime::parse_from_rfc2822(value?.to_str().ok()?).ok()?;
+	Some(date.timestamp() as u64)
+}
+
+fn hash(data: &str) -> String {
+	let mut hasher = DefaultHasher::new();
+	data.hash(&mut hasher);
+	format!("{:016x}", hasher.finish())
+}
+
+// Generate the key of a response variant, using the values of the headers listed in its Vary header.
+fn variant_key(url: &str, vary: &[String], headers: &HeaderMap) -> String {
+	let mut data = url.to_owned();
+	for name in vary {
+		let value = HeaderName::from_bytes(name.as_bytes()).ok()
+			.and_then(|n| headers.get(n)).and_then(|v| v.to_str().ok()).unwrap_or("");
+		data = [&data, "\n", name, ":", value].concat();
+	}
+	hash(&data)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::response_meta;
+	use actix_web::http::{header, header::{HeaderMap, HeaderValue}, StatusCode};
+
+	fn headers(list: &[(header::HeaderName, &'static str)]) -> HeaderMap {
+		let mut map = HeaderMap::new();
+		for (key, value) in list {
+			map.append(key.to_owned(), HeaderValue::from_static(*value));
+		}
+		map
+	}
+
+	#[test]
+	fn cache_control() {
+		let meta = response_meta("/ /", StatusCode::OK, &headers(&[(header::CACHE_CONTROL, "public, max-age=60, stale-while-revalidate=30, stale-if-error=600")])).expect("response should be cacheable");
+		assert_eq!((meta.fresh, meta.swr, meta.sie), (60, 30, 600));
+
+		let meta = response_meta("/ /", StatusCode::OK, &headers(&[(header::CACHE_CONTROL, "max-age=60, s-maxage=120")])).expect("response should be cacheable");
+		assert_eq!(meta.fresh, 120);
+	}
+
+	#[test]
+	fn uncacheable() {
+		assert!(response_meta("/ /", StatusCode::OK, &HeaderMap::new()).is_none());
+		assert!(response_meta("/ /", StatusCode::OK, &headers(&[(header::CACHE_CONTROL, "max-age=60, private")])).is_none());
+		assert!(response_meta("/ /", StatusCode::OK, &headers(&[(header::CACHE_CONTROL, "max-age=60"), (header::SET_COOKIE, "a=b")])).is_none());
+		assert!(response_meta("/ /", StatusCode::OK, &headers(&[(header::CACHE_CONTROL, "max-age=60"), (header::VARY, "*")])).is_none());
+		assert!(response_meta("/ /", StatusCode::INTERNAL_SERVER_ERROR, &headers(&[(header::CACHE_CONTROL, "max-age=60")])).is_none());
+	}
+
+	#[test]
+	fn vary_and_headers() {
+		let meta = response_meta("/ /", StatusCode::OK, &headers(&[(header::CACHE_CONTROL, "max-age=60"), (header::VARY, "Accept-Encoding, Origin"), (header::CONNECTION, "close")])).expect("response should be cacheable");
+		assert_eq!(meta.vary, vec!["accept-encoding", "origin"]);
+		assert!(meta.headers.iter().all(|(k, _)| k != "connection"));
+	}
+}
@@ -1,8 +1,18 @@
-// Certs.rs handles certificate parsing.
+// Certs.rs handles certificate parsing, along with the TLS settings used when connecting to upstream servers.
 extern crate rustls;
 extern crate webpki;
+extern crate webpki_roots;
+extern crate actix_connect;
+extern crate actix_service;
+extern crate actix_web;
+extern crate tokio_tcp;
 use std::{collections, fs::File, io::{Read, BufReader}, sync::Arc};
 use rustls::{sign, ResolvesServerCert, SignatureScheme, sign::{any_supported_type, CertifiedKey}, internal::pemfile::{certs, pkcs8_private_keys}};
+use rustls::{ClientConfig, RootCertStore, Certificate, ServerCertVerifier, ServerCertVerified, TLSError};
+use self::actix_connect::{default_connector, Connect, Connection, ConnectError};
+use self::actix_service::{Service, ServiceExt};
+use self::tokio_tcp::TcpStream;
+use actix_web::http::Uri;
 
 #[derive(Clone)]
 pub struct ResolveCert {
@@ -82,3 +92,113 @@ impl ResolvesServerCert for ResolveCert {
         }
     }
 }
+
+/* Create the TLS settings for connecting to a proxy's upstream servers. If a CA bundle is provided, it replaces the default trust roots.
+A client certificate and key can be provided for upstreams which require client authentication, and certificate verification can be disabled for development. */
+pub fn client_config(ca: Option<&str>, cert: Option<&str>, key: Option<&str>, insecure: bool) -> Result<ClientConfig, String> {
+	let mut config = ClientConfig::new();
+
+	if let Some(ca) = ca {
+		let mut ca_file = BufReader::new(File::open(ca).map_err(|_| ["Unable to open ", ca, "!"].concat())?);
+		match config.root_store.add_pem_file(&mut ca_file) {
+			Ok((valid, _)) if valid > 0 => (),
+			_ => return Err(["Unable to parse ", ca, "!"].concat()),
+		}
+	} else {
+		config.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+	}
+
+	match (cert, key) {
+		(Some(cert), Some(key)) => {
+			let mut cert_file = BufReader::new(File::open(cert).map_err(|_| ["Unable to open ", cert, "!"].concat())?);
+			let mut key_file = BufReader::new(File::open(key).map_err(|_| ["Unable to open ", key, "!"].concat())?);
+			let cert_chain = certs(&mut cert_file).map_err(|_| ["Unable to parse ", cert, "!"].concat())?;
+			let mut keys = pkcs8_private_keys(&mut key_file).map_err(|_| ["Unable to parse ", key, "!"].concat())?;
+
+			if keys.is_empty() {
+				return Err([key, " contains no valid pkcs8 keys!\n\nNote: You can convert your keyfile into pkcs8 using the command below.\nopenssl pkcs8 -topk8 -nocrypt -in oldkey.pem -out newkey.pem"].concat())
+			}
+			config.set_single_client_cert(cert_chain, keys.remove(0));
+		},
+		(None, None) => (),
+		_ => return Err("A client certificate and key must both be provided!".to_owned()),
+	}
+
+	if insecure {
+		config.dangerous().set_certificate_verifier(Arc::new(NoVerify));
+	}
+	Ok(config)
+}
+
+/* Create a connector which makes TLS connections use a different server name than the upstream's host.
+The name is used for both SNI and certificate verification, which allows upstreams to be specified by their IP address. */
+pub fn sni_connector(name: Uri) -> impl Service<Request=Connect<Uri>, Response=Connection<Uri, TcpStream>, Error=ConnectError> + Clone {
+	default_connector().map(move |conn: Connection<Uri, TcpStream>| Connection::new(conn.into_parts().0, name.to_owned()))
+}
+
+// NoVerify accepts any certificate presented by an upstream server. It should only be used for development.
+struct NoVerify;
+
+impl ServerCertVerifier for NoVerify {
+	fn verify_server_cert(&self, _roots: &RootCertStore, _presented_certs: &[Certificate], _dns_name: webpki::DNSNameRef, _ocsp_response: &[u8]) -> Result<ServerCertVerified, TLSError> {
+		Ok(ServerCertVerified::assertion())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::client_config;
+	use rustls::{ClientConfig, ClientSession, NoClientAuth, ServerConfig, ServerSession, Session, TLSError, internal::pemfile::{certs, pkcs8_private_keys}};
+	use std::{fs::File, io::BufReader, sync::Arc};
+	use super::webpki::DNSNameRef;
+
+	// Complete a TLS handshake in memory between the provided client settings and a server using the default certificate.
+	fn handshake(config: ClientConfig) -> Result<(), TLSError> {
+		let cert = certs(&mut BufReader::new(File::open("ssl/default.crt").expect("default certificate should exist"))).expect("default certificate should parse");
+		let mut keys = pkcs8_private_keys(&mut BufReader::new(File::open("ssl/default.pem").expect("default key should exist"))).expect("default key should parse");
+		let mut server_config = ServerConfig::new(NoClientAuth::new());
+		server_config.set_single_cert(cert, keys.remove(0)).expect("default certificate should be usable");
+
+		let mut server = ServerSession::new(&Arc::new(server_config));
+		let mut client = ClientSession::new(&Arc::new(config), DNSNameRef::try_from_ascii_str("localhost").expect("name should be valid"));
+		let mut buf = Vec::new();
+		while client.is_handshaking() || server.is_handshaking() {
+			buf.clear();
+			client.write_tls(&mut buf).expect("client should write to memory");
+			server.read_tls(&mut &buf[..]).expect("server should read from memory");
+			server.process_new_packets()?;
+
+			buf.clear();
+			server.write_tls(&mut buf).expect("server should write to memory");
+			client.read_tls(&mut &buf[..]).expect("client should read from memory");
+			client.process_new_packets()?;
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn custom_ca() {
+		let config = client_config(Some("ssl/default.crt"), None, None, false).expect("CA bundle should load");
+		assert_eq!(config.root_store.len(), 1);
+		assert!(client_config(Some("ssl/default.pem"), None, None, false).is_err());
+		assert!(client_config(Some("ssl/missing.crt"), None, None, false).is_err());
+	}
+
+	#[test]
+	fn client_cert() {
+		assert!(client_config(None, Some("ssl/default.crt"), Some("ssl/default.pem"), false).is_ok());
+
+		// Both halves of the client certificate are required, and the key file has to contain a key.
+		assert_eq!(client_config(None, Some("ssl/default.crt"), None, false).err(), Some("A client certificate and key must both be provided!".to_owned()));
+		assert_eq!(client_config(None, None, Some("ssl/default.pem"), false).err(), Some("A client certificate and key must both be provided!".to_owned()));
+		assert!(client_config(None, Some("ssl/default.pem"), Some("ssl/default.crt"), false).is_err());
+	}
+
+	#[test]
+	fn insecure() {
+		// The default certificate isn't valid for any name, so it is only accepted when verification is disabled.
+		assert!(handshake(client_config(Some("ssl/default.crt"), None, None, false).expect("CA bundle should load")).is_err());
+		assert!(handshake(client_config(Some("ssl/default.crt"), None, None, true).expect("CA bundle should load")).is_ok());
+		assert!(handshake(client_config(None, None, None, true).expect("default roots should load")).is_ok());
+	}
+}
@@ -0,0 +1,611 @@
+// Cgi.rs handles running scripts, either as CGI programs or through FastCGI application servers (such as PHP-FPM).
+
+// FastCGI records and name-value pairs have their lengths checked before being cast, so this can be ignored.
+#![allow(clippy::cast_possible_truncation)]
+
+extern crate actix;
+extern crate actix_web;
+extern crate futures;
+extern crate bytes;
+
+use futures::{Future, Sink, Stream, future, sync::{mpsc, oneshot}};
+use bytes::Bytes;
+use std::{ffi::OsString, io::{self, Read, Write, BufRead, BufReader}, iter, net::{TcpStream, ToSocketAddrs}, path::Path, process::{Command, Stdio}, sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}}, thread, time::{Duration, Instant}};
+#[cfg(unix)]
+use std::os::unix::{net::UnixStream, fs::PermissionsExt};
+use self::actix::Arbiter;
+use actix_web::{dev::RequestHead, error, http::{header, header::{HeaderName, HeaderValue}, ContentEncoding, StatusCode}, middleware::BodyEncoding, HttpResponse, Error};
+use stream::{trim_prefix, trim_host};
+use headers::{Rules, Vars};
+use {ui, upstream};
+use upload::Upload;
+
+// FastCGI record types.
+const BEGIN_REQUEST: u8 = 1;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+
+// The largest amount of content that can fit in a single FastCGI record.
+const MAX_RECORD: usize = 65_535;
+
+// The most CGI scripts and FastCGI requests which can run at once. Each one needs its own threads, so requests past this are rejected instead of starting more threads.
+const MAX_RUNNING: usize = 256;
+static RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+// Permit reserves one of the MAX_RUNNING slots for a request, until it is dropped.
+struct Permit;
+
+impl Permit {
+	fn acquire() -> Option<Self> {
+		if RUNNING.fetch_add(1, Ordering::SeqCst) >= MAX_RUNNING {
+			RUNNING.fetch_sub(1, Ordering::SeqCst);
+			return None
+		}
+		Some(Self)
+	}
+}
+
+impl Drop for Permit {
+	fn drop(&mut self) {
+		RUNNING.fetch_sub(1, Ordering::SeqCst);
+	}
+}
+
+// The response sent when too many scripts are already running.
+fn busy(name: &str, smaller: bool) -> Box<Future<Item=HttpResponse, Error=Error>> {
+	println!("[Warn]: {} was rejected, as {} scripts are already running.", name, MAX_RUNNING);
+	Box::new(future::ok(ui::http_error(StatusCode::SERVICE_UNAVAILABLE, "503 Service Unavailable", "The server is running too many scripts to handle the request.", smaller)))
+}
+
+// A script found on the disk, along with the paths passed to it.
+pub struct Script {
+	pub root: String,
+	pub filename: String,
+	pub name: String,
+	pub path_info: String,
+}
+
+/* Find the script requested by a path, within the document root. The script is the shortest part of the path which is a file, and the rest of the path is passed to it as PATH_INFO.
+If the path points to a folder, the index script in that folder is used. None is returned if no script can be found. */
+pub fn find_script(root: &str, prefix: &str, path: &str, index: &str) -> Option<Script> {
+	let root = root.trim_end_matches('/');
+	let mut path = if path.starts_with('/') {path.to_owned()} else {["/", path].concat()};
+	if path.ends_with('/') {
+		path = [&*path, index].concat()
+	}
+
+	let ends = path.match_indices('/').map(|(i, _)| i).skip(1).chain(iter::once(path.len()));
+	for end in ends {
+		let filename = [root, &path[..end]].concat();
+		if Path::new(&filename).is_file() {
+			return Some(Script {
+				root: root.to_owned(),
+				filename,
+				name: [prefix.trim_end_matches('/'), &path[..end]].concat(),
+				path_info: path[end..].to_owned(),
+			})
+		}
+	}
+	None
+}
+
+/* Build the CGI parameters for a request. Request headers are passed as HTTP_* parameters, except for the Proxy header, which CGI applications can confuse with the HTTP_PROXY environment variable.
+If header rules are provided, the request header rules are applied to the headers first, as they are for requests sent to other upstream servers. */
+pub fn params(head: &RequestHead, script: &Script, client_ip: &str, https: bool, rules: Option<&Rules>) -> Vec<(String, String)> {
+	let mut headers = head.headers.to_owned();
+	if let Some(r) = rules.filter(|r| !r.is_empty()) {
+		r.request(&mut headers, &Vars::new(head, client_ip, https));
+	}
+
+	let host_port = head.headers.get(header::HOST).and_then(|h| h.to_str().ok()).or_else(|| head.uri.host()).unwrap_or("");
+	let port = match trim_prefix(":", trim_host(host_port)) {
+		"" => if https {"443"} else {"80"},
+		p => p,
+	};
+	let header_str = |name: HeaderName| headers.get(name).and_then(|h| h.to_str().ok()).unwrap_or("");
+	let version = format!("{:?}", head.version);
+
+	let base: [(&str, &str); 16] = [
+		("GATEWAY_INTERFACE", "CGI/1.1"),
+		("SERVER_SOFTWARE", "KatWebX"),
+		("SERVER_PROTOCOL", &version),
+		("SERVER_NAME", &host_port[..host_port.len()-trim_host(host_port).len()]),
+		("SERVER_PORT", port),
+		("REQUEST_METHOD", head.method.as_str()),
+		("REQUEST_URI", head.uri.path_and_query().map_or("/", |p| p.as_str())),
+		("QUERY_STRING", head.uri.query().unwrap_or("")),
+		("DOCUMENT_ROOT", &script.root),
+		("SCRIPT_FILENAME", &script.filename),
+		("SCRIPT_NAME", &script.name),
+		("PATH_INFO", &script.path_info),
+		("REMOTE_ADDR", client_ip),
+		("CONTENT_TYPE", header_str(header::CONTENT_TYPE)),
+		("CONTENT_LENGTH", header_str(header::CONTENT_LENGTH)),
+		("REDIRECT_STATUS", "200"),
+	];
+	let mut params: Vec<(String, String)> = base.iter().map(|(k, v)| ((*k).to_owned(), (*v).to_owned())).collect();
+	if https {
+		params.push(("HTTPS".to_owned(), "on".to_owned()))
+	}
+
+	for (key, value) in headers.iter() {
+		if key == header::CONTENT_TYPE || key == header::CONTENT_LENGTH || key.as_str() == "proxy" {
+			continue
+		}
+
+		let name = ["HTTP_", &key.as_str().to_uppercase().replace('-', "_")].concat();
+		let value = value.to_str().unwrap_or("");
+		let sep = if key == header::COOKIE {"; "} else {", "};
+		match params.iter_mut().find(|(k, _)| *k == name) {
+			Some((_, v)) => *v = [&**v, sep, value].concat(),
+			None => params.push((name, value.to_owned())),
+		}
+	}
+	params
+}
+
+/* Parse the headers at the start of a CGI response, returning the status, the headers, and how many bytes they took up. None is returned if the headers are incomplete.
+The status is taken from the Status header, and defaults to 302 if a Location header is set, or 200 otherwise. */
+fn parse_head(buf: &[u8]) -> Option<io::Result<(StatusCode, Vec<(HeaderName, HeaderValue)>, usize)>> {
+	let end = (0..buf.len()).find(|&i| buf[i] == b'\n' && (buf[i+1..].starts_with(b"\n") || buf[i+1..].starts_with(b"\r\n")))?;
+	let len = if buf[end+1] == b'\n' {end + 2} else {end + 3};
+
+	let mut status = None;
+	let mut headers = Vec::new();
+	for line in String::from_utf8_lossy(&buf[..end]).lines() {
+		let mut parts = line.splitn(2, ':');
+		let (name, value) = match (parts.next(), parts.next()) {
+			(Some(n), Some(v)) => (n.trim(), v.trim()),
+			_ => return Some(Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid header in CGI response"))),
+		};
+
+		if name.eq_ignore_ascii_case("status") {
+			status = value.split(' ').next().and_then(|s| s.parse::<u16>().ok()).and_then(|s| StatusCode::from_u16(s).ok());
+			continue
+		}
+		if let (Ok(n), Ok(v)) = (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value)) {
+			headers.push((n, v));
+		}
+	}
+
+	let status = status.unwrap_or_else(|| if headers.iter().any(|(n, _)| n == header::LOCATION) {StatusCode::FOUND} else {StatusCode::OK});
+	Some(Ok((status, headers, len)))
+}
+
+// The status and headers of a CGI response.
+type Head = io::Result<(StatusCode, Vec<(HeaderName, HeaderValue)>)>;
+
+// Conn is any connection to a FastCGI application server.
+pub trait Conn: Read + Write + Send {}
+impl<T: Read + Write + Send> Conn for T {}
+
+// Connect to a FastCGI application server, listening on either a TCP address or a Unix socket.
+pub fn connect(addr: &str, timeout: Duration) -> io::Result<Box<Conn>> {
+	#[cfg(unix)] {
+		if let Some((socket, _)) = upstream::unix_socket(addr) {
+			let conn = UnixStream::connect(socket)?;
+			conn.set_read_timeout(Some(timeout))?;
+			conn.set_write_timeout(Some(timeout))?;
+			return Ok(Box::new(conn))
+		}
+	}
+
+	let sock = addr.to_socket_addrs()?.next().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Unable to resolve address"))?;
+	let conn = TcpStream::connect_timeout(&sock, timeout)?;
+	conn.set_read_timeout(Some(timeout))?;
+	conn.set_write_timeout(Some(timeout))?;
+	Ok(Box::new(conn))
+}
+
+// Write a FastCGI record, splitting the content into multiple records if it is too long. Empty content is written as a single empty record, which ends a stream.
+fn write_record(conn: &mut Conn, kind: u8, content: &[u8]) -> io::Result<()> {
+	let mut chunks: Vec<&[u8]> = content.chunks(MAX_RECORD).collect();
+	if chunks.is_empty() {
+		chunks.push(&[]);
+	}
+
+	for chunk in chunks {
+		let len = chunk.len() as u16;
+		conn.write_all(&[1, kind, 0, 1, (len >> 8) as u8, len as u8, 0, 0])?;
+		conn.write_all(chunk)?;
+	}
+	Ok(())
+}
+
+// Read a FastCGI record, returning its type and content. None is returned if the connection was closed.
+fn read_record(conn: &mut Conn) -> io::Result<Option<(u8, Vec<u8>)>> {
+	let mut rec = [0; 8];
+	match conn.read_exact(&mut rec) {
+		Ok(()) => (),
+		Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+		Err(e) => return Err(e),
+	}
+	let len = (usize::from(rec[4]) << 8) | usize::from(rec[5]);
+	let mut content = vec![0; len + usize::from(rec[6])];
+	conn.read_exact(&mut content)?;
+	content.truncate(len);
+	Ok(Some((rec[1], content)))
+}
+
+// Encode CGI parameters as FastCGI name-value pairs.
+fn encode_params(params: &[(String, String)]) -> Vec<u8> {
+	let mut buf = Vec::new();
+	for (name, value) in params {
+		encode_len(&mut buf, name.len());
+		encode_len(&mut buf, value.len());
+		buf.extend_from_slice(name.as_bytes());
+		buf.extend_from_slice(value.as_bytes());
+	}
+	buf
+}
+
+// Encode the length of a FastCGI name or value. Lengths under 128 take up a single byte, while longer lengths take up four.
+fn encode_len(buf: &mut Vec<u8>, len: usize) {
+	if len < 128 {
+		buf.push(len as u8)
+	} else {
+		buf.extend_from_slice(&(len as u32 | 0x8000_0000).to_be_bytes())
+	}
+}
+
+// Output parses the headers at the start of a CGI response, and then passes the rest of the response through to the client.
+struct Output {
+	head_tx: Option<oneshot::Sender<Head>>,
+	out_tx: Option<mpsc::Sender<Bytes>>,
+	head: Vec<u8>,
+}
+
+impl Output {
+	fn new(head_tx: oneshot::Sender<Head>, out_tx: mpsc::Sender<Bytes>) -> Self {
+		Self { head_tx: Some(head_tx), out_tx: Some(out_tx), head: Vec::new() }
+	}
+
+	// Handle part of the response. An error is returned if the headers are invalid, or if the client has disconnected.
+	fn write(&mut self, data: &[u8]) -> io::Result<()> {
+		if self.head_tx.is_none() {
+			return self.send(Bytes::from(data))
+		}
+
+		self.head.extend_from_slice(data);
+		if let Some(parsed) = parse_head(&self.head) {
+			let (status, headers, used) = parsed?;
+			if let Some(tx) = self.head_tx.take() {
+				let _ = tx.send(Ok((status, headers)));
+			}
+			let rest = Bytes::from(&self.head[used..]);
+			self.head = Vec::new();
+			return self.send(rest)
+		}
+		Ok(())
+	}
+
+	fn send(&mut self, data: Bytes) -> io::Result<()> {
+		if data.is_empty() {
+			return Ok(())
+		}
+
+		let tx = self.out_tx.take().ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Client disconnected"))?;
+		self.out_tx = Some(tx.send(data).wait().map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Client disconnected"))?);
+		Ok(())
+	}
+
+	// Finish the response. If the response failed before the headers were received, the client is sent an error page, otherwise the error is only logged.
+	fn finish(mut self, result: io::Result<()>, name: &str) {
+		let result = match (result, &self.head_tx) {
+			(Ok(()), Some(_)) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The response ended before its headers were sent")),
+			(res, _) => res,
+		};
+
+		if let Err(err) = result {
+			match self.head_tx.take() {
+				Some(tx) => {let _ = tx.send(Err(err));},
+				None => println!("[Warn]: {} failed while streaming the response: {}", name, err),
+			}
+		}
+	}
+}
+
+// Pass the request body through a channel, so that it can be read from another thread.
+fn forward_body(body: Upload) -> mpsc::Receiver<Bytes> {
+	let (body_tx, body_rx) = mpsc::channel(4);
+	Arbiter::spawn(body.map_err(|_| ()).forward(body_tx.sink_map_err(|_| ())).map(|_| ()));
+	body_rx
+}
+
+// Turn the response passed back by an Output into an HttpResponse, or an error page if the response was invalid.
+fn response(name: String, head_rx: oneshot::Receiver<Head>, out_rx: mpsc::Receiver<Bytes>, smaller: bool) -> Box<Future<Item=HttpResponse, Error=Error>> {
+	Box::new(head_rx.then(move |res| {
+		let res = res.unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The handler stopped unexpectedly")));
+		let (status, headers) = match res {
+			Ok(h) => h,
+			Err(err) => {
+				println!("[Warn]: {} failed: {}", name, err);
+				return Err(Error::from(ui::http_error(StatusCode::BAD_GATEWAY, "502 Bad Gateway", "The server was unable to get a valid response from the script.", smaller)))
+			},
+		};
+
+		let mut resp = HttpResponse::build(status);
+		for (key, value) in headers {
+			if key == header::CONTENT_ENCODING {
+				resp.encoding(ContentEncoding::Identity); // Make sure compressed data doesn't get recompressed.
+			}
+			resp.header(key, value);
+		}
+		Ok(resp.streaming(out_rx.map_err(|_| error::ErrorBadGateway("Response ended unexpectedly"))))
+	}))
+}
+
+/* Send a request to a FastCGI application server, and stream the response back.
+The FastCGI connection is blocking, so it's handled in a separate thread, with the request and response bodies being passed through channels. */
+pub fn fastcgi(addr: &str, params: Vec<(String, String)>, body: Upload, timeout: Duration, smaller: bool) -> Box<Future<Item=HttpResponse, Error=Error>> {
+	let name = ["FastCGI request to ", addr].concat();
+	let permit = match Permit::acquire() {
+		Some(p) => p,
+		None => return busy(&name, smaller),
+	};
+
+	let (head_tx, head_rx) = oneshot::channel();
+	let (out_tx, out_rx) = mpsc::channel(4);
+	let body_rx = forward_body(body);
+
+	let thread_addr = addr.to_owned();
+	let thread_name = name.to_owned();
+	thread::spawn(move || {
+		let _permit = permit;
+		let mut output = Output::new(head_tx, out_tx);
+		let res = run_fastcgi(&thread_addr, &params, body_rx, &mut output, timeout);
+		output.finish(res, &thread_name);
+	});
+
+	response(name, head_rx, out_rx, smaller)
+}
+
+// Run a FastCGI request over a blocking connection.
+fn run_fastcgi(addr: &str, params: &[(String, String)], body_rx: mpsc::Receiver<Bytes>, output: &mut Output, timeout: Duration) -> io::Result<()> {
+	let mut conn = connect(addr, timeout)?;
+
+	// The request is sent as a responder, and the connection is closed once it has been handled.
+	write_record(&mut *conn, BEGIN_REQUEST, &[0, 1, 0, 0, 0, 0, 0, 0])?;
+
+	write_record(&mut *conn, PARAMS, &encode_params(params))?;
+	write_record(&mut *conn, PARAMS, &[])?;
+
+	for chunk in body_rx.wait() {
+		match chunk {
+			Ok(c) => write_record(&mut *conn, STDIN, &c)?,
+			Err(_) => break,
+		}
+	}
+	write_record(&mut *conn, STDIN, &[])?;
+
+	while let Some((kind, content)) = read_record(&mut *conn)? {
+		match kind {
+			STDOUT => output.write(&content)?,
+			STDERR if !content.is_empty() => println!("[Warn]: FastCGI application at {} logged: {}", addr, String::from_utf8_lossy(&content).trim()),
+			END_REQUEST => return Ok(()),
+			_ => (),
+		}
+	}
+	Ok(())
+}
+
+// Check if a script can be run. On platforms other than Unix, every file is assumed to be runnable.
+#[cfg(unix)]
+pub fn is_executable(path: &str) -> bool {
+	std::fs::metadata(path).map(|m| m.permissions().mode() & 0o111 != 0).unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+pub fn is_executable(_path: &str) -> bool {
+	true
+}
+
+// The PATH given to CGI scripts, as the rest of the server's environment isn't passed to them. On Unix, this is a minimal PATH containing the standard program folders.
+#[cfg(unix)]
+fn cgi_path() -> OsString {
+	OsString::from("/usr/local/bin:/usr/bin:/bin")
+}
+
+#[cfg(not(unix))]
+fn cgi_path() -> OsString {
+	std::env::var_os("PATH").unwrap_or_default()
+}
+
+/* Run a CGI script, and stream the response back. The script is stopped if it runs for longer than the timeout.
+Writing the request body, reading the response, and watching the script's run time are each done in a separate thread, as they all block. */
+pub fn exec(script: &Script, params: Vec<(String, String)>, body: Upload, timeout: u64, smaller: bool) -> Box<Future<Item=HttpResponse, Error=Error>> {
+	let name = ["CGI script ", &script.filename].concat();
+	let permit = match Permit::acquire() {
+		Some(p) => p,
+		None => return busy(&name, smaller),
+	};
+
+	let (head_tx, head_rx) = oneshot::channel();
+	let (out_tx, out_rx) = mpsc::channel(4);
+	let body_rx = forward_body(body);
+
+	let filename = script.filename.to_owned();
+	let thread_name = name.to_owned();
+	thread::spawn(move || {
+		let _permit = permit;
+		let mut output = Output::new(head_tx, out_tx);
+		let res = run_cgi(&filename, &params, body_rx, &mut output, timeout);
+		output.finish(res, &thread_name);
+	});
+
+	response(name, head_rx, out_rx, smaller)
+}
+
+// Run a CGI script as a child process, passing the request body to its stdin and reading the response from its stdout.
+fn run_cgi(filename: &str, params: &[(String, String)], body_rx: mpsc::Receiver<Bytes>, output: &mut Output, timeout: u64) -> io::Result<()> {
+	let dir = Path::new(filename).parent().unwrap_or_else(|| Path::new("."));
+	let mut child = Command::new(filename)
+		.env_clear()
+		.env("PATH", cgi_path())
+		.envs(params.iter().map(|(k, v)| (k, v)))
+		.current_dir(dir)
+		.stdin(Stdio::piped())
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.spawn()?;
+	let (stdin, stdout, stderr) = (child.stdin.take(), child.stdout.take(), child.stderr.take());
+	let child = Arc::new(Mutex::new(child));
+
+	// The watchdog stops the script once it has run for too long, and cleans up the process once it exits.
+	let (watched, name) = (child.to_owned(), filename.to_owned());
+	let deadline = Instant::now() + Duration::from_secs(timeout);
+	thread::spawn(move || loop {
+		thread::sleep(Duration::from_millis(100));
+		let mut child = watched.lock().unwrap_or_else(|e| e.into_inner());
+		match child.try_wait() {
+			Ok(None) if Instant::now() >= deadline => {
+				let _ = child.kill();
+				let _ = child.wait();
+				return println!("[Warn]: CGI script {} ran for longer than {} seconds, and was stopped.", name, timeout)
+			},
+			Ok(None) => (),
+			_ => return,
+		}
+	});
+
+	if let Some(mut stdin) = stdin {
+		thread::spawn(move || {
+			for chunk in body_rx.wait() {
+				match chunk {
+					Ok(c) => if stdin.write_all(&c).is_err() {break},
+					Err(_) => break,
+				}
+			}
+		});
+	}
+
+	if let Some(stderr) = stderr {
+		let name = filename.to_owned();
+		thread::spawn(move || {
+			for line in BufReader::new(stderr).lines() {
+				match line {
+					Ok(l) => println!("[Warn]: CGI script {} logged: {}", name, l),
+					Err(_) => break,
+				}
+			}
+		});
+	}
+
+	let mut stdout = stdout.ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Unable to read the script's output"))?;
+	let mut buf = [0; 8192];
+	loop {
+		let n = match stdout.read(&mut buf) {
+			Ok(0) => return Ok(()),
+			Ok(n) => n,
+			Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+			Err(e) => return Err(e),
+		};
+
+		// If the client disconnects, there's no reason to keep the script running.
+		if let Err(err) = output.write(&buf[..n]) {
+			let _ = child.lock().unwrap_or_else(|e| e.into_inner()).kill();
+			return Err(err)
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{encode_params, parse_head, read_record, run_fastcgi, write_record, Output, END_REQUEST, MAX_RECORD, PARAMS, STDIN, STDOUT};
+	use actix_web::http::{header, StatusCode};
+	use bytes::Bytes;
+	use futures::{Future, Sink, Stream, sync::{mpsc, oneshot}};
+	use std::{io::Cursor, net::TcpListener, thread, time::Duration};
+
+	#[test]
+	fn head() {
+		let (status, headers, len) = parse_head(b"Content-Type: text/plain\r\nStatus: 404 Not Found\r\n\r\nbody").expect("head should be complete").expect("head should be valid");
+		assert_eq!((status, len), (StatusCode::NOT_FOUND, 51));
+		assert_eq!(headers.len(), 1);
+		assert_eq!(headers[0].0, header::CONTENT_TYPE);
+
+		let (status, _, len) = parse_head(b"Location: /a\n\n").expect("head should be complete").expect("head should be valid");
+		assert_eq!((status, len), (StatusCode::FOUND, 14));
+
+		assert!(parse_head(b"Content-Type: text/plain\r\n").is_none());
+		assert!(parse_head(b"invalid\n\n").expect("head should be complete").is_err());
+	}
+
+	#[test]
+	fn record_round_trip() {
+		let long: Vec<u8> = (0..MAX_RECORD + 10).map(|i| i as u8).collect();
+		let mut conn = Cursor::new(Vec::new());
+		write_record(&mut conn, STDOUT, &long).expect("records should be written");
+		write_record(&mut conn, STDOUT, &[]).expect("records should be written");
+		conn.set_position(0);
+
+		let mut content = Vec::new();
+		while let Some((kind, data)) = read_record(&mut conn).expect("records should be read") {
+			assert_eq!(kind, STDOUT);
+			if data.is_empty() {
+				break
+			}
+			content.extend_from_slice(&data);
+		}
+		assert_eq!(content, long);
+		assert!(read_record(&mut conn).expect("records should be read").is_none());
+	}
+
+	#[test]
+	fn params_round_trip() {
+		let params = vec![("SHORT".to_owned(), "a".to_owned()), ("LONG".to_owned(), "b".repeat(300))];
+		let mut conn = Cursor::new(Vec::new());
+		write_record(&mut conn, PARAMS, &encode_params(&params)).expect("records should be written");
+		conn.set_position(0);
+
+		let (kind, data) = read_record(&mut conn).expect("records should be read").expect("a record should be read");
+		assert_eq!(kind, PARAMS);
+		assert_eq!(data, [&[5, 1][..], &b"SHORTa"[..], &[4, 0x80, 0, 1, 44][..], &b"LONG"[..], "b".repeat(300).as_bytes()].concat());
+	}
+
+	#[test]
+	fn fastcgi_stub() {
+		// The stub application server reads a single request, and answers it with a fixed response.
+		let listener = TcpListener::bind("127.0.0.1:0").expect("stub server should start");
+		let addr = listener.local_addr().expect("stub server should have an address").to_string();
+		let server = thread::spawn(move || {
+			let (mut conn, _) = listener.accept().expect("stub server should accept a connection");
+			let (mut params, mut stdin) = (Vec::new(), Vec::new());
+			while let Some((kind, data)) = read_record(&mut conn).expect("records should be read") {
+				match kind {
+					PARAMS => params.extend_from_slice(&data),
+					STDIN if data.is_empty() => break,
+					STDIN => stdin.extend_from_slice(&data),
+					_ => (),
+				}
+			}
+			write_record(&mut conn, STDOUT, b"Status: 201 Created\r\nX-Test: 1\r\n\r\nhello").expect("records should be written");
+			write_record(&mut conn, STDOUT, &[]).expect("records should be written");
+			write_record(&mut conn, END_REQUEST, &[0; 8]).expect("records should be written");
+			(params, stdin)
+		});
+
+		let (body_tx, body_rx) = mpsc::channel(4);
+		let _ = body_tx.send(Bytes::from_static(b"request body")).wait().expect("body should be sent");
+		let (head_tx, head_rx) = oneshot::channel();
+		let (out_tx, out_rx) = mpsc::channel(4);
+		let mut output = Output::new(head_tx, out_tx);
+		let params = vec![("SCRIPT_NAME".to_owned(), "/index.php".to_owned())];
+		run_fastcgi(&addr, &params, body_rx, &mut output, Duration::from_secs(5)).expect("request should succeed");
+		output.finish(Ok(()), "test");
+
+		let (status, headers) = head_rx.wait().expect("head should be sent").expect("head should be valid");
+		assert_eq!(status, StatusCode::CREATED);
+		assert_eq!(headers.len(), 1);
+		assert_eq!((headers[0].0.as_str(), headers[0].1.to_str().ok()), ("x-test", Some("1")));
+		let body: Vec<Bytes> = out_rx.wait().collect::<Result<_, _>>().expect("body should be received");
+		assert_eq!(body.concat(), b"hello");
+
+		let (sent_params, stdin) = server.join().expect("stub server should finish");
+		assert_eq!(sent_params, encode_params(&params));
+		assert_eq!(stdin, b"request body");
+	}
+}
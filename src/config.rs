@@ -5,18 +5,32 @@ extern crate toml;
 extern crate regex;
 extern crate exitcode;
 extern crate base64;
-use std::{collections::HashMap, fs, process, path::Path};
+extern crate rustls;
+extern crate actix_web;
+use std::{collections::HashMap, fs, process, path::Path, sync::Arc, net::{IpAddr, SocketAddr}, time::Duration};
+use rustls::ClientConfig;
+use actix_web::{client::{Client, ClientBuilder, Connector}, http::{header::HeaderMap, Uri}};
 use regex::{RegexSet, Regex, NoExpand};
 use trim_prefix;
+use upstream::{self, Pool, Strategy, HealthCheck, Breaker, Canary, Retry, Timeouts};
+use cache::Cache;
+use certs;
+use tcp;
+use mirror::Mirror;
+use headers::{Actions, Rule, Rules};
 
 // ConfStruct objects are used for parsing the configuration, and aren't used for KatWebX's internal routing. KatWebX uses the Config object for storing and accessing the parsed content.
 #[derive(Clone, Deserialize)]
 struct ConfStruct {
 	server: ConfStructServer,
 	content: ConfStructContent,
-	proxy: Option<Vec<ConfStructPrRe>>,
+	proxy: Option<Vec<ConfStructProxy>>,
+	canary: Option<Vec<ConfStructCanary>>,
 	redir: Option<Vec<ConfStructPrRe>>,
 	auth: Option<Vec<ConfStructAuth>>,
+	headers: Option<Vec<ConfStructHeaders>>,
+	passthrough: Option<Vec<ConfStructPassthrough>>,
+	stream: Option<Vec<ConfStructStream>>,
 }
 
 #[derive(Clone, Deserialize)]
@@ -24,11 +38,21 @@ struct ConfStructServer {
 	http_addr: Option<String>,
 	tls_addr: Option<String>,
 	stream_timeout: Option<usize>,
+	keep_alive: Option<usize>,
+	websocket_timeout: Option<usize>,
 	log_format: Option<String>,
 	cert_folder: Option<String>,
 	root_folder: Option<String>,
 	copy_chunk_size: Option<u64>,
-	prefer_chacha_poly: Option<bool>
+	prefer_chacha_poly: Option<bool>,
+	cache_folder: Option<String>,
+	cache_size: Option<u64>,
+	proxy_debug: Option<bool>,
+	max_body_size: Option<u64>,
+	trusted_proxies: Option<Vec<String>>,
+	http_proxy_protocol: Option<bool>,
+	tls_proxy_protocol: Option<bool>,
+	proxy_protocol_sources: Option<Vec<String>>
 }
 
 #[derive(Clone, Deserialize)]
@@ -38,7 +62,9 @@ struct ConfStructContent {
 	compress_files: Option<bool>,
 	hsts: Option<bool>,
 	hide: Option<Vec<String>>,
-	smaller_default: Option<bool>
+	smaller_default: Option<bool>,
+	cgi: Option<Vec<String>>,
+	cgi_timeout: Option<u64>
 }
 
 #[derive(Clone, Deserialize)]
@@ -47,27 +73,173 @@ struct ConfStructPrRe {
 	dest: String
 }
 
+#[derive(Clone, Deserialize)]
+struct ConfStructProxy {
+	location: String,
+	dest: Option<String>,
+	upstream: Option<Vec<ConfStructUpstream>>,
+	strategy: Option<String>,
+	health: Option<ConfStructHealth>,
+	max_fails: Option<usize>,
+	fail_timeout: Option<u64>,
+	cache: Option<bool>,
+	forward: Option<Vec<String>>,
+	rewrite_redirects: Option<bool>,
+	redirects: Option<Vec<(String, String)>>,
+	cookie_domains: Option<Vec<(String, String)>>,
+	cookie_paths: Option<Vec<(String, String)>>,
+	tls_ca: Option<String>,
+	tls_cert: Option<String>,
+	tls_key: Option<String>,
+	tls_name: Option<String>,
+	tls_insecure: Option<bool>,
+	script_root: Option<String>,
+	script_index: Option<String>,
+	mirror: Option<String>,
+	mirror_percent: Option<usize>,
+	retries: Option<usize>,
+	retry_on: Option<Vec<String>>,
+	retry_non_idempotent: Option<bool>,
+	retry_buffer: Option<usize>,
+	connect_timeout: Option<u64>,
+	first_byte_timeout: Option<u64>,
+	read_timeout: Option<u64>,
+	total_timeout: Option<u64>,
+	max_body_size: Option<u64>,
+	buffer_uploads: Option<bool>,
+	proxy_protocol: Option<u8>
+}
+
+#[derive(Clone, Deserialize)]
+struct ConfStructCanary {
+	header: Option<String>,
+	cookie: Option<String>,
+	percent: Option<u64>,
+	#[serde(flatten)]
+	proxy: ConfStructProxy
+}
+
+#[derive(Clone, Deserialize)]
+struct ConfStructHealth {
+	path: Option<String>,
+	interval: Option<u64>,
+	status: Option<u16>,
+	rise: Option<usize>,
+	fall: Option<usize>
+}
+
+#[derive(Clone, Deserialize)]
+struct ConfStructUpstream {
+	dest: String,
+	weight: Option<usize>
+}
+
 #[derive(Clone, Deserialize)]
 struct ConfStructAuth {
 	location: String,
 	login: String
 }
 
+#[derive(Clone, Deserialize)]
+struct ConfStructPassthrough {
+	name: String,
+	dest: String,
+	proxy_protocol: Option<u8>
+}
+
+#[derive(Clone, Deserialize)]
+struct ConfStructStream {
+	listen: String,
+	dest: String,
+	proxy_protocol: Option<u8>
+}
+
+#[derive(Clone, Deserialize)]
+struct ConfStructHeaders {
+	location: String,
+	request_remove: Option<Vec<String>>,
+	request_set: Option<Vec<(String, String)>>,
+	request_add: Option<Vec<(String, String)>>,
+	response_remove: Option<Vec<String>>,
+	response_set: Option<Vec<(String, String)>>,
+	response_add: Option<Vec<(String, String)>>
+}
+
+// A proxy location, along with the upstream servers that it sends requests to.
+pub struct Proxy {
+	pub location: String,
+	pub pool: Arc<Pool>,
+	pub cache: bool,
+	pub forward: Vec<String>,
+	pub rewrite_redirects: bool,
+	pub redirects: Vec<(String, String)>,
+	pub cookie_domains: Vec<(String, String)>,
+	pub cookie_paths: Vec<(String, String)>,
+	tls: Option<Arc<ClientConfig>>,
+	tls_name: Option<Uri>,
+	proxy_protocol: Option<u8>,
+	pub script_root: Option<String>,
+	pub script_index: String,
+	pub mirror: Option<Mirror>,
+	pub retry: Option<Retry>,
+	pub timeouts: Timeouts,
+	pub template: Option<String>,
+	pub max_body_size: Option<u64>,
+	pub buffer_uploads: bool,
+	pub canary: bool,
+}
+
+impl Proxy {
+	/* Create a client for sending requests to the proxy's upstream servers, which waits up to the specified timeout for a response. The default TLS settings are used unless custom TLS settings are set.
+	If a Unix socket is provided, every connection made by the client goes to that socket instead. If the proxy sends PROXY protocol headers, the client's address is sent to TCP upstreams when connecting. */
+	#[cfg_attr(not(unix), allow(unused_variables))]
+	pub fn client(&self, socket: Option<&str>, timeout: Duration, source: Option<SocketAddr>) -> Client {
+		let builder = ClientBuilder::new().timeout(timeout);
+
+		#[cfg(unix)] {
+			if let Some(socket) = socket {
+				let connector = Connector::new().connector(upstream::UnixConnector::new(socket)).timeout(self.timeouts.connect);
+				return builder.connector(connector.finish()).finish()
+			}
+		}
+
+		let connector = Connector::new().timeout(self.timeouts.connect);
+		let connector = match self.tls.to_owned() {
+			Some(tls) => connector.rustls(tls),
+			None => connector,
+		};
+
+		match (self.proxy_protocol.and_then(|v| source.map(|s| (v, s))), self.tls_name.to_owned()) {
+			(Some((version, source)), name) => builder.connector(connector.connector(tcp::proxy_connector(version, source, name)).finish()).finish(),
+			(None, Some(name)) => builder.connector(connector.connector(certs::sni_connector(name)).finish()).finish(),
+			(None, None) => builder.connector(connector.finish()).finish(),
+		}
+	}
+}
+
 // The shared configuration object that KatWebX uses. Routing info shouldn't be accessed directly, it should be accessed through the handle_path function instead.
 pub struct Config {
 	pub caching_timeout: i64,
-	pub stream_timeout: usize,
+	pub keep_alive: usize,
+	pub websocket_timeout: usize,
 	pub hsts: bool,
 	hidden: Vec<String>,
 	lredir: Vec<String>,
 	lproxy: Vec<String>,
 	hiddenx: RegexSet,
+	lcgi: Vec<String>,
+	cgix: RegexSet,
+	pub cgi_timeout: u64,
 	redirx: RegexSet,
 	proxyx: RegexSet,
 	authx: RegexSet,
 	redirmap: HashMap<String, String>,
-	proxymap: HashMap<String, String>,
+	proxymap: HashMap<String, Arc<Proxy>>,
+	canarymap: HashMap<String, Canary>,
 	authmap: HashMap<String, String>,
+	pub header_rules: Arc<Rules>,
+	pub passthrough: HashMap<String, (String, Option<u8>)>,
+	pub streams: Vec<(String, String, Option<u8>)>,
 	pub protect: bool,
 	pub compress_files: bool,
 	pub chacha: bool,
@@ -77,7 +249,14 @@ pub struct Config {
 	pub cert_folder: String,
 	pub root_folder: String,
 	pub max_streaming_len: u64,
-	pub smaller_default: bool
+	pub smaller_default: bool,
+	pub proxy_debug: bool,
+	pub max_body_size: u64,
+	pub trusted_proxies: Vec<String>,
+	pub http_proxy_protocol: bool,
+	pub tls_proxy_protocol: bool,
+	pub proxy_protocol_sources: Vec<String>,
+	pub cache: Arc<Cache>
 }
 
 impl Config {
@@ -104,11 +283,13 @@ impl Config {
 		// Parse the provided content into a Config object, using default values if an item can't be found.
 		Self {
 			caching_timeout: conft.content.caching_timeout.unwrap_or(12),
-			stream_timeout: conft.server.stream_timeout.unwrap_or(20),
+			keep_alive: conft.server.keep_alive.or(conft.server.stream_timeout).unwrap_or(20),
+			websocket_timeout: conft.server.websocket_timeout.unwrap_or(20),
 			hsts: conft.content.hsts.unwrap_or(false),
 			hidden: {
 				let mut tmp = conft.content.hide.to_owned().unwrap_or_else(Vec::new);
 				tmp.push(conft.server.cert_folder.to_owned().unwrap_or_else(|| "ssl".to_owned()));
+				tmp.push(conft.server.cache_folder.to_owned().unwrap_or_else(|| "cache".to_owned()));
 				tmp.push("redir".to_owned());
 				tmp.sort_unstable();
 				tmp
@@ -120,6 +301,15 @@ impl Config {
 					process::exit(exitcode::CONFIG);
 				})
 			},
+			lcgi: conft.content.cgi.to_owned().unwrap_or_else(Vec::new).into_iter().filter(|item| !item.starts_with("r#")).collect(),
+			cgix: {
+				parse_regex(conft.content.cgi.to_owned().unwrap_or_else(Vec::new)).unwrap_or_else(|err| {
+					println!("[Fatal]: Unable to parse configuration! Debugging information will be printed below.");
+					println!("{}", err);
+					process::exit(exitcode::CONFIG);
+				})
+			},
+			cgi_timeout: conft.content.cgi_timeout.unwrap_or(30),
 			lredir: {
 				let mut tmp = Vec::new();
 				for item in conft.redir.to_owned().unwrap_or_else(Vec::new) {
@@ -142,6 +332,11 @@ impl Config {
 			redirmap: {
 				let mut tmp = HashMap::new();
 				for item in conft.redir.unwrap_or_else(Vec::new) {
+					check_captures(&item.location, &item.dest).unwrap_or_else(|err| {
+						println!("[Fatal]: Unable to parse configuration! Debugging information will be printed below.");
+						println!("{}", err);
+						process::exit(exitcode::CONFIG);
+					});
 					tmp.insert(item.location, item.dest);
 				}
 				tmp
@@ -168,8 +363,40 @@ impl Config {
 			},
 			proxymap: {
 				let mut tmp = HashMap::new();
+				let stream_timeout = conft.server.stream_timeout.unwrap_or(20) as u64;
 				for item in conft.proxy.unwrap_or_else(Vec::new) {
-					tmp.insert(item.location, item.dest);
+					let proxy = parse_proxy(&item, stream_timeout).unwrap_or_else(|err| {
+						println!("[Fatal]: Unable to parse configuration! Debugging information will be printed below.");
+						println!("{}", err);
+						process::exit(exitcode::CONFIG);
+					});
+					tmp.insert(item.location, Arc::new(proxy));
+				}
+
+				// Canaries are stored as separate proxy locations, so that they have their own upstreams and settings.
+				for item in conft.canary.to_owned().unwrap_or_else(Vec::new) {
+					let location = item.proxy.location.to_owned();
+					let proxy = parse_proxy(&item.proxy, stream_timeout).and_then(|proxy| {
+						if tmp.contains_key(&location) {
+							Ok(proxy)
+						} else {
+							Err(["The canary for ", &location, " doesn't match any proxy location!"].concat())
+						}
+					}).unwrap_or_else(|err| {
+						println!("[Fatal]: Unable to parse configuration! Debugging information will be printed below.");
+						println!("{}", err);
+						process::exit(exitcode::CONFIG);
+					});
+					let key = ["canary#", &location].concat();
+					tmp.insert(key.to_owned(), Arc::new(Proxy {location: key, canary: true, ..proxy}));
+				}
+				tmp
+			},
+			canarymap: {
+				let mut tmp = HashMap::new();
+				for item in conft.canary.unwrap_or_else(Vec::new) {
+					let canary = Canary::new(item.header.as_ref().map(String::as_str), item.cookie.as_ref().map(String::as_str), item.percent.unwrap_or(0));
+					tmp.insert(item.proxy.location, canary);
 				}
 				tmp
 			},
@@ -192,6 +419,42 @@ impl Config {
 				}
 				tmp
 			},
+			header_rules: {
+				let mut tmp = Vec::new();
+				for item in conft.headers.unwrap_or_else(Vec::new) {
+					let rule = parse_headers(&item).map_err(|err| ["Unable to parse header rules for ", &item.location, ": ", &err].concat()).unwrap_or_else(|err| {
+						println!("[Fatal]: Unable to parse configuration! Debugging information will be printed below.");
+						println!("{}", err);
+						process::exit(exitcode::CONFIG);
+					});
+					tmp.push(rule);
+				}
+				Arc::new(Rules(tmp))
+			},
+			passthrough: {
+				let mut tmp = HashMap::new();
+				for item in conft.passthrough.unwrap_or_else(Vec::new) {
+					let send = check_addr(&item.dest).and_then(|_| proxy_protocol_version(item.proxy_protocol)).unwrap_or_else(|err| {
+						println!("[Fatal]: Unable to parse configuration! Debugging information will be printed below.");
+						println!("{}", err);
+						process::exit(exitcode::CONFIG);
+					});
+					tmp.insert(item.name.to_lowercase(), (item.dest, send));
+				}
+				tmp
+			},
+			streams: {
+				let mut tmp = Vec::new();
+				for item in conft.stream.unwrap_or_else(Vec::new) {
+					let send = check_addr(&item.listen).and_then(|_| check_addr(&item.dest)).and_then(|_| proxy_protocol_version(item.proxy_protocol)).unwrap_or_else(|err| {
+						println!("[Fatal]: Unable to parse configuration! Debugging information will be printed below.");
+						println!("{}", err);
+						process::exit(exitcode::CONFIG);
+					});
+					tmp.push((item.listen, item.dest, send));
+				}
+				tmp
+			},
 			protect: conft.content.protect.unwrap_or(true),
 			compress_files: conft.content.compress_files.unwrap_or(true),
 			log_format: conft.server.log_format.unwrap_or_else(|| "minimal".to_owned()),
@@ -202,6 +465,13 @@ impl Config {
 			max_streaming_len: conft.server.copy_chunk_size.unwrap_or(65_536),
 			chacha: conft.server.prefer_chacha_poly.unwrap_or(false),
 			smaller_default: conft.content.smaller_default.unwrap_or(false),
+			proxy_debug: conft.server.proxy_debug.unwrap_or(false),
+			max_body_size: conft.server.max_body_size.unwrap_or(0),
+			trusted_proxies: conft.server.trusted_proxies.unwrap_or_else(Vec::new),
+			http_proxy_protocol: conft.server.http_proxy_protocol.unwrap_or(false),
+			tls_proxy_protocol: conft.server.tls_proxy_protocol.unwrap_or(false),
+			proxy_protocol_sources: conft.server.proxy_protocol_sources.unwrap_or_else(Vec::new),
+			cache: Arc::new(Cache::new(conft.server.cache_folder.unwrap_or_else(|| "cache".to_owned()), conft.server.cache_size.unwrap_or(256)*1_048_576)),
 		}
 	}
 
@@ -209,9 +479,10 @@ impl Config {
 	Special cases:
 	- If HTTP authentication fails, "unauth" will be returned as the path, and "redir" will be returned as the host.
 	- If a redirect is set, "redir" will be returned as the host, and the location to redirect to will be returned as the path.
-	- If a reverse proxy is set, "proxy" will be returned as the host, and the URL to proxy will be returned as the path.
+	- If a reverse proxy is set, "proxy" will be returned as the host, the remaining path will be returned as the path, and the proxy location will be returned in place of the full path. The location can be passed to get_proxy.
+	  If the request matches the location's canary rule, the canary's location is returned instead.
 	- If a normal file is being served, an optional full path (host+path) will be returned, along with the path and host.*/
-	pub fn handle_path(&self, path: &str, host: &str, auth: &str) -> (String, String, Option<String>) {
+	pub fn handle_path(&self, path: &str, host: &str, auth: &str, headers: &HeaderMap, client_ip: &str) -> (String, String, Option<String>) {
 		let mut host = trim_port(host);
 		let hostn = host.to_owned();
 
@@ -236,10 +507,13 @@ impl Config {
 			}
 		}
 
-	// Check if a path has redirects set, and then return the redirects if they are present. If a regex redirect is set, trim matching content from the path, and then add the non-matching content to the redirect destination. 
+	// Check if a path has redirects set, and then return the redirects if they are present. If a regex redirect is set, either fill in the capture references in the redirect destination, or trim matching content from the path and then add the non-matching content to the redirect destination. 
 	if self.redirx.is_match(fp) {
 		if let Some(regx) = self.redirx.matches(fp).iter().next() {
 			if let Some(link) = self.redirmap.get(&["r#", &self.redirx.patterns()[regx]].concat()) {
+				if !capture_refs(link).is_empty() {
+					return (expand_regex(&self.redirx.patterns()[regx], fp, link), "redir".to_owned(), None)
+				}
 				return ([link.to_owned(), trim_regex(&self.redirx.patterns()[regx], fp)].concat(), "redir".to_owned(), None)
 			}
 		}
@@ -250,24 +524,25 @@ impl Config {
 		}
 	}
 
-	// Check if a reverse proxy is set, and return the proxy URL if it is present.
+	// Check if a reverse proxy is set, and return the proxy location if it is present. Canary rules are checked before the location is returned.
 	if self.proxyx.is_match(fp) {
 		if let Some(regx) = self.proxyx.matches(fp).iter().next() {
-			if let Some(link) = self.proxymap.get(&["r#", &self.proxyx.patterns()[regx]].concat()) {
-				return ([link.to_owned(), trim_regex(&self.proxyx.patterns()[regx], fp)].concat(), "proxy".to_owned(), None)
+			let loc = ["r#", &self.proxyx.patterns()[regx]].concat();
+			if self.proxymap.contains_key(&loc) {
+				let loc = self.canary_location(loc, headers, client_ip);
+				let path = match self.proxymap.get(&loc).and_then(|p| p.template.as_ref()) {
+					Some(template) => expand_regex(&self.proxyx.patterns()[regx], fp, template),
+					None => trim_regex(&self.proxyx.patterns()[regx], fp),
+				};
+				return (path, "proxy".to_owned(), Some(loc))
 			}
 		}
 	}
-	if self.lproxy.binary_search(&hostn).is_ok() {
-		if let Some(link) = self.proxymap.get(host) {
-			return ([link, path].concat(), "proxy".to_owned(), None)
-		}
+	if self.lproxy.binary_search(&hostn).is_ok() && self.proxymap.contains_key(host) {
+		return (path.to_owned(), "proxy".to_owned(), Some(self.canary_location(hostn, headers, client_ip)))
 	}
 
-	// If the host doesn't exist or is a location the client isn't allowed to access, use the default host instead.
-	if self.hidden.binary_search(&hostn).is_ok() || self.hiddenx.is_match(&hostn) || host.is_empty() || &host[..1] == "." || host.contains('/') || host.contains('\\') || !Path::new(&hostn).exists() {
-		host = "html"
-	}
+	host = self.host_folder(host);
 
 	// If we're serving a folder, return the index file from that folder.
 	let pathn;
@@ -281,6 +556,49 @@ impl Config {
 	let full_path = [host, &*pathn].concat();
 	(pathn, host.to_owned(), Some(full_path))
 }
+
+	// Get the location that a proxied request should be sent to, which is the location's canary if the request matches its canary rule.
+	fn canary_location(&self, location: String, headers: &HeaderMap, client_ip: &str) -> String {
+		match self.canarymap.get(&location) {
+			Some(canary) if canary.matches(headers, client_ip) => ["canary#", &location].concat(),
+			_ => location,
+		}
+	}
+
+	// Get the folder that a host's files are served from. If the host doesn't exist or is a location the client isn't allowed to access, the default host is used instead.
+	pub fn host_folder<'a>(&self, host: &'a str) -> &'a str {
+		let host = trim_port(host);
+		if self.hidden.binary_search(&host.to_owned()).is_ok() || self.hiddenx.is_match(host) || host.is_empty() || &host[..1] == "." || host.contains('/') || host.contains('\\') || !Path::new(host).exists() {
+			return "html"
+		}
+		host
+	}
+
+	// Check if a full path (host+path) is within a folder that CGI scripts are run from.
+	pub fn is_cgi(&self, full_path: &str) -> bool {
+		self.cgix.is_match(full_path) || self.lcgi.iter().any(|folder| full_path.starts_with(folder.as_str()))
+	}
+
+	// Keep the health of upstream servers which are still used after a config reload. The old Config object is the one being replaced.
+	pub fn inherit_health(&self, old: &Self) {
+		for (location, proxy) in &self.proxymap {
+			if let Some(prev) = old.proxymap.get(location) {
+				proxy.pool.inherit_health(&prev.pool);
+			}
+		}
+	}
+
+	// Start health checks for all proxy locations which have them enabled. This should only be called on the active Config object, as the checks run until the Config object is dropped.
+	pub fn start_health_checks(&self) {
+		for proxy in self.proxymap.values() {
+			upstream::spawn_health_checks(&proxy.pool);
+		}
+	}
+
+	// Get the proxy for a location returned by handle_path.
+	pub fn get_proxy(&self, location: &str) -> Option<Arc<Proxy>> {
+		self.proxymap.get(location).cloned()
+	}
 }
 
 // Trim the port from an IPv4 address, IPv6 address, or domain:port.
@@ -304,6 +622,86 @@ fn trim_regex(regex: &str, root: &str) -> String {
 	r.replace_all(root, NoExpand("")).to_string()
 }
 
+// Fill in the capture references in dest, using the captures from matching the regex against root.
+fn expand_regex(regex: &str, root: &str, dest: &str) -> String {
+	let r = Regex::new(regex).unwrap_or_else(|_| Regex::new("$x").unwrap());
+	let mut expanded = String::new();
+	if let Some(caps) = r.captures(root) {
+		caps.expand(dest, &mut expanded);
+	}
+	expanded
+}
+
+// Get the capture references ($1, ${1}, $name or ${name}) used in a destination. $$ is a literal $, and a $ which isn't followed by a name is left as-is.
+fn capture_refs(dest: &str) -> Vec<&str> {
+	let mut refs = Vec::new();
+	let mut rest = dest;
+	while let Some(i) = rest.find('$') {
+		rest = &rest[i+1..];
+		if rest.starts_with('$') {
+			rest = &rest[1..];
+			continue
+		}
+
+		let name = if rest.starts_with('{') {
+			rest.find('}').map_or("", |end| &rest[1..end])
+		} else {
+			&rest[..rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or_else(|| rest.len())]
+		};
+		if !name.is_empty() {
+			refs.push(name);
+		}
+	}
+	refs
+}
+
+/* Check that every capture reference in a destination exists in the location's regex. Returns whether the destination uses any capture references.
+Destinations of locations which aren't regex never have captures filled in, so any $ characters in them are treated as literal text. */
+fn check_captures(location: &str, dest: &str) -> Result<bool, String> {
+	let refs = capture_refs(dest);
+	if refs.is_empty() || !location.starts_with("r#") {
+		return Ok(false)
+	}
+
+	let regex = Regex::new(&location[2..]).map_err(|err| err.to_string())?;
+	for name in refs {
+		let exists = match name.parse::<usize>() {
+			Ok(n) => n < regex.captures_len(),
+			Err(_) => regex.capture_names().any(|n| n == Some(name)),
+		};
+		if !exists {
+			return Err(["The capture group \"", name, "\" used in ", dest, " doesn't exist in ", location, "!"].concat())
+		}
+	}
+	Ok(true)
+}
+
+// Check if a destination is an HTTPS upstream specified by its IP address, which doesn't have a server name that can be verified.
+fn https_ip(dest: &str) -> bool {
+	match dest.parse::<Uri>() {
+		Ok(uri) => uri.scheme_str() == Some("https") && uri.host().map_or(false, |h| h.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().is_ok()),
+		Err(_) => false,
+	}
+}
+
+// Split a proxy destination into the upstream and the path. None is returned if the destination has no path, or is a FastCGI server.
+fn split_dest(dest: &str) -> Option<(&str, &str)> {
+	let i = match upstream::unix_socket(dest) {
+		Some((socket, _)) => dest[5+socket.len()..].find(":/").map(|i| 5 + socket.len() + i)?,
+		None if upstream::fastcgi_addr(dest).is_none() => {
+			let host = dest.find("://")? + 3;
+			host + dest[host..].find('/')?
+		},
+		None => return None,
+	};
+
+	let (base, path) = dest.split_at(i);
+	if base.contains('$') {
+		return None
+	}
+	Some((base, path.trim_start_matches(':')))
+}
+
 // Turn an array into a Vec<String>, only adding items which contain regex.
 // All regex strings must start with r#, so that the program knows they are regex. The r# will be trimmed from the string before the regex is parsed.
 fn array_get_regex(array: Vec<String>) -> Vec<String> {
@@ -316,6 +714,188 @@ fn array_get_regex(array: Vec<String>) -> Vec<String> {
 	tmp
 }
 
+// Turn a proxy section into a Proxy object. A single dest is treated as a pool containing only that upstream. Timeouts which aren't set use the stream timeout.
+fn parse_proxy(item: &ConfStructProxy, stream_timeout: u64) -> Result<Proxy, String> {
+	let strategy = item.strategy.to_owned().unwrap_or_else(|| "round-robin".to_owned());
+	let strategy = Strategy::parse(&strategy).ok_or_else(|| ["Unknown proxy strategy \"", &strategy, "\" for ", &item.location, "!"].concat())?;
+
+	// If dest contains capture references, its path is filled in from the location's captures for each request, instead of having the unmatched part of the URL added to it.
+	let mut upstreams = Vec::new();
+	let mut template = None;
+	if let Some(dest) = item.dest.to_owned() {
+		if check_captures(&item.location, &dest)? {
+			let (base, path) = split_dest(&dest).ok_or_else(|| ["Capture references can only be used in the path of dest, but ", &dest, " uses them elsewhere!"].concat())?;
+			template = Some(path.to_owned());
+			upstreams.push((base.to_owned(), 1));
+		} else {
+			upstreams.push((dest, 1));
+		}
+	}
+	for up in item.upstream.to_owned().unwrap_or_else(Vec::new) {
+		if !capture_refs(&up.dest).is_empty() {
+			return Err(["Capture references can only be used in dest, not upstream, for ", &item.location, "!"].concat())
+		}
+		upstreams.push((up.dest, up.weight.unwrap_or(1)));
+	}
+
+	if upstreams.is_empty() {
+		return Err(["The proxy for ", &item.location, " has no dest or upstream set!"].concat())
+	}
+	for (dest, _) in &upstreams {
+		if let Some((_, prefix)) = upstream::unix_socket(dest) {
+			if cfg!(not(unix)) {
+				return Err(["Unix socket upstreams aren't supported on this platform, but one is used for ", &item.location, "!"].concat())
+			}
+			if !prefix.is_empty() && !prefix.starts_with('/') {
+				return Err(["Invalid Unix socket destination \"", dest, "\" for ", &item.location, "!"].concat())
+			}
+		}
+	}
+
+	// Setting max_fails to 0 disables passive failure detection.
+	let max_fails = item.max_fails.unwrap_or(5);
+	let breaker = if max_fails == 0 {None} else {
+		Some(Breaker {max_fails, fail_timeout: item.fail_timeout.unwrap_or(30)})
+	};
+
+	let forward = item.forward.to_owned().unwrap_or_else(|| vec!["x-forwarded-for".to_owned(), "x-forwarded-proto".to_owned(), "x-forwarded-host".to_owned(), "x-forwarded-port".to_owned()]);
+	let mut forward: Vec<String> = forward.iter().map(|h| h.to_lowercase()).collect();
+	forward.sort_unstable();
+	forward.dedup();
+	for h in &forward {
+		match h.as_str() {
+			"x-forwarded-for" | "x-forwarded-proto" | "x-forwarded-host" | "x-forwarded-port" | "forwarded" => (),
+			_ => return Err(["Unknown forwarding header \"", h, "\" for ", &item.location, "!"].concat()),
+		}
+	}
+
+	// Setting retries to 0 disables retries.
+	let retry_on = item.retry_on.to_owned().unwrap_or_else(|| vec!["connect".to_owned(), "reset".to_owned()]);
+	for class in &retry_on {
+		match class.as_str() {
+			"connect" | "timeout" | "reset" => (),
+			_ if class.len() == 3 && class.parse::<u16>().ok().filter(|c| *c >= 500 && *c < 600).is_some() => (),
+			_ => return Err(["Unknown retry failure \"", class, "\" for ", &item.location, "!"].concat()),
+		}
+	}
+	let retry = match item.retries.unwrap_or(0) {
+		0 => None,
+		attempts => Some(Retry {
+			attempts,
+			on: retry_on,
+			non_idempotent: item.retry_non_idempotent.unwrap_or(false),
+			buffer: item.retry_buffer.unwrap_or(65_536),
+		}),
+	};
+
+	// Setting total_timeout to 0 disables it.
+	let timeouts = Timeouts {
+		connect: Duration::from_secs(item.connect_timeout.unwrap_or(stream_timeout)),
+		first_byte: Duration::from_secs(item.first_byte_timeout.unwrap_or(stream_timeout)),
+		read: Duration::from_secs(item.read_timeout.unwrap_or(stream_timeout)),
+		total: item.total_timeout.filter(|t| *t > 0).map(Duration::from_secs),
+	};
+
+	if let Some(mirror) = item.mirror.as_ref() {
+		if upstream::fastcgi_addr(mirror).is_some() || (upstream::unix_socket(mirror).is_some() && cfg!(not(unix))) {
+			return Err(["The mirror for ", &item.location, " must be an HTTP upstream!"].concat())
+		}
+	}
+
+	let tls_name = match item.tls_name.to_owned() {
+		Some(name) => Some(["https://", &name, "/"].concat().parse::<Uri>().map_err(|_| ["Invalid tls_name \"", &name, "\" for ", &item.location, "!"].concat())?),
+		None => None,
+	};
+	let tls_insecure = item.tls_insecure.unwrap_or(false);
+	if tls_insecure {
+		println!("[Warn]: Certificate verification is disabled for upstream servers of {}! This should only be used for development.", item.location);
+	}
+
+	// Custom TLS settings are only used if they are needed, as the default client's settings are shared between requests.
+	let tls = if item.tls_ca.is_some() || item.tls_cert.is_some() || item.tls_key.is_some() || tls_name.is_some() || tls_insecure {
+		let config = certs::client_config(item.tls_ca.as_ref().map(String::as_str), item.tls_cert.as_ref().map(String::as_str), item.tls_key.as_ref().map(String::as_str), tls_insecure)
+			.map_err(|err| ["Unable to load TLS settings for ", &item.location, ": ", &err].concat())?;
+		Some(Arc::new(config))
+	} else {
+		None
+	};
+
+	// Health checks for HTTPS upstreams use the proxy's TLS settings, or the default trust roots if it doesn't have any.
+	if let Some(h) = item.health.as_ref() {
+		if h.interval == Some(0) {
+			return Err(["The health check interval for ", &item.location, " must be at least 1 second!"].concat())
+		}
+		if item.tls_name.is_none() {
+			if let Some((dest, _)) = upstreams.iter().find(|(dest, _)| https_ip(dest)) {
+				return Err(["Health checks for ", dest, " need tls_name to be set, as the upstream is specified by its IP address!"].concat())
+			}
+		}
+	}
+	let health = match item.health.to_owned() {
+		Some(h) => Some(HealthCheck {
+			path: h.path.unwrap_or_else(|| "/".to_owned()),
+			interval: h.interval.unwrap_or(10),
+			status: h.status.unwrap_or(200),
+			rise: h.rise.unwrap_or(2),
+			fall: h.fall.unwrap_or(3),
+			tls: match tls.to_owned() {
+				Some(t) => t,
+				None => Arc::new(certs::client_config(None, None, None, false)?),
+			},
+			tls_name: item.tls_name.to_owned(),
+		}),
+		None => None,
+	};
+	let proxy_protocol = proxy_protocol_version(item.proxy_protocol).map_err(|err| [&err, " (", &item.location, ")"].concat())?;
+
+	Ok(Proxy {
+		location: item.location.to_owned(),
+		pool: Arc::new(Pool::new(strategy, upstreams, health, breaker)),
+		cache: item.cache.unwrap_or(false),
+		forward,
+		rewrite_redirects: item.rewrite_redirects.unwrap_or(true),
+		redirects: item.redirects.to_owned().unwrap_or_else(Vec::new),
+		cookie_domains: item.cookie_domains.to_owned().unwrap_or_else(Vec::new),
+		cookie_paths: item.cookie_paths.to_owned().unwrap_or_else(Vec::new),
+		tls,
+		tls_name,
+		proxy_protocol,
+		script_root: item.script_root.to_owned(),
+		script_index: item.script_index.to_owned().unwrap_or_else(|| "index.php".to_owned()),
+		mirror: item.mirror.to_owned().map(|dest| Mirror::new(dest, item.mirror_percent.unwrap_or(100))),
+		retry,
+		timeouts,
+		template,
+		max_body_size: item.max_body_size,
+		buffer_uploads: item.buffer_uploads.unwrap_or(false),
+		canary: false,
+	})
+}
+
+// Check that an address used for layer 4 proxying has a port.
+fn check_addr(addr: &str) -> Result<(), String> {
+	match addr.rfind(':').map(|i| addr[i+1..].parse::<u16>()) {
+		Some(Ok(_)) => Ok(()),
+		_ => Err(["The address \"", addr, "\" must include a port!"].concat()),
+	}
+}
+
+// Check that a PROXY protocol version is supported. A version of 0 means that PROXY protocol headers aren't sent.
+fn proxy_protocol_version(version: Option<u8>) -> Result<Option<u8>, String> {
+	match version.unwrap_or(0) {
+		0 => Ok(None),
+		v if v <= 2 => Ok(Some(v)),
+		v => Err(["Unsupported PROXY protocol version ", &v.to_string(), "!"].concat()),
+	}
+}
+
+// Turn a headers section into a Rule object.
+fn parse_headers(item: &ConfStructHeaders) -> Result<Rule, String> {
+	let request = Actions::new(&item.request_remove.to_owned().unwrap_or_else(Vec::new), &item.request_set.to_owned().unwrap_or_else(Vec::new), &item.request_add.to_owned().unwrap_or_else(Vec::new))?;
+	let response = Actions::new(&item.response_remove.to_owned().unwrap_or_else(Vec::new), &item.response_set.to_owned().unwrap_or_else(Vec::new), &item.response_add.to_owned().unwrap_or_else(Vec::new))?;
+	Rule::new(&item.location, request, response)
+}
+
 // Turn an array into parsed regex.
 fn parse_regex(array: Vec<String>) -> Result<RegexSet, regex::Error> {
 	RegexSet::new(&array_get_regex(array))
@@ -334,8 +914,17 @@ pub const DEFAULT_CONFIG: &str = r##"# conf.toml - KatWebX's Default Configurati
 
 # stream_timeout controls the maximum amount of time the connection can stay open (in seconds).
 # The default value should be good enough for transfering small files. If you are serving large files, increasing this is recommended.
+# It is also the default for keep_alive and the timeouts of each [[proxy]].
 #stream_timeout = 20
 
+# keep_alive controls how long an idle client connection is kept open for further requests (in seconds).
+#keep_alive = 20
+
+# websocket_timeout controls the maximum amount of time a websocket connection can go without receving ping/pong frames before it is automatically closed.
+# Note that KatWebX's websocket proxy doesn't send ping/pong frames, it only proxies them.
+# The default value should be good enough for 90% of use cases, don't adjust this unless you need to.
+#websocket_timeout = 20
+
 # copy_chunk_size adjusts the maximum file size (in bytes) which can be directly copied into the response.
 # Files larger than this value are copied into the response in chunks of this size, which increases latency.
 # When the file is smaller than this value, it is copied directly into the response. This can heavily increase RAM usage on busy servers.
@@ -357,6 +946,34 @@ log_format = "simple"
 # root_folder controls the web server root. The default folder (html) and per-domain folders will be stored in here.
 #root_folder = "."
 
+# cache_folder controls the folder used for storing cached responses from proxies which have caching enabled.
+#cache_folder = "cache"
+
+# cache_size controls the maximum size of the cache folder (in megabytes). Once it is full, the least recently used responses are removed.
+# Responses larger than 1/16th of this size are never cached.
+#cache_size = 256
+
+# proxy_debug adds the reason a proxied request failed to the error page sent to the client. The reason is always logged, even when this is disabled.
+# This should only be enabled when debugging issues with upstream servers, as it can reveal details about your network.
+#proxy_debug = false
+
+# max_body_size limits the size of request bodies sent to proxies and CGI scripts (in bytes). Larger requests are rejected with a 413 error. Setting this to 0 disables this.
+# Requests with a larger Content-Length are rejected without reading the body, while requests without one are cut off once they reach the limit.
+# Expect: 100-continue is answered by KatWebX itself, and isn't forwarded to upstream servers.
+#max_body_size = 0
+
+# trusted_proxies specifies a list of client IP addresses which are allowed to send forwarding headers (X-Forwarded-For, Forwarded, etc...) to proxied servers.
+# Forwarding headers sent by any other client are removed, so that clients can't lie about their IP address to upstream servers.
+#trusted_proxies = ["127.0.0.1", "::1"]
+
+# http_proxy_protocol and tls_proxy_protocol allow the HTTP and HTTPS listeners to accept PROXY protocol (version 1 or 2) headers, which are sent by load balancers to pass on the client's address.
+# The client's address from the header is used for logging, authentication, canaries and forwarding headers. Changing these requires a restart.
+#http_proxy_protocol = false
+#tls_proxy_protocol = false
+
+# proxy_protocol_sources specifies a list of IP addresses which are allowed to send PROXY protocol headers. Connections from any other address are handled normally.
+#proxy_protocol_sources = ["127.0.0.1", "::1"]
+
 
 [content] # Content related settings.
 # protect allows prevention of some common security issues through the use of HTTP security headers.
@@ -364,11 +981,13 @@ log_format = "simple"
 #protect = true
 
 # caching_timeout controls how long the content is cached by the client (in hours).
+# Files are sent with ETag and Last-Modified headers, so once this expires, clients can check whether a file has changed without downloading it again.
 #caching_timeout = 12
 
-# compress_files allows the server to save brotli compressed versions of files to the disk.
+# compress_files allows the server to save compressed versions of files (.br, .zst and .gz) to the disk, in whichever format a client prefers.
 # When this is disabled, all data will be compressed on-the-fly, severely reducing peformance.
-# Note that this only prevents the creation of new brotli files, existing brotli files will still be served.
+# Note that this only prevents the creation of new compressed files, existing compressed files will still be served.
+# The compressed version is picked using the q-values in the client's Accept-Encoding header, preferring brotli, then zstd, then gzip. The simple and simpleplus log formats show which one was sent.
 #compress_files = true
 
 # hsts forces all clients to use HTTPS, through the use of HTTP headers and redirects.
@@ -384,12 +1003,18 @@ hide = ["src", "target"]
 # This can make your server slightly more secure, but it is not necessary for the vast majority of deployments.
 #smaller_default = false
 
+# cgi specifies a list of folders (host+path) which contain CGI scripts. Scripts in these folders are run instead of being served, and can handle any request method. This field supports regex.
+# Scripts must be executable, and are stopped if they run for longer than cgi_timeout seconds.
+#cgi = ["localhost/cgi-bin/", "r#.*/cgi-bin/.*"]
+#cgi_timeout = 30
+
 
-#[[proxy]] # HTTP reverse proxy
+#[[proxy]] # HTTP and websocket reverse proxy
 # The host to be proxied. When using regex in this field, a URL without the protocol is provided as input instead.
 #location = "proxy.local"
 
 # The destination for proxied requests. When using HTTPS, a valid TLS certificate is required.
+# HTTPS upstreams can be proxied over HTTP/2, but HTTP upstreams only use HTTP/1.1, and HTTP trailers aren't passed through. This means gRPC services can't be proxied.
 #dest = "https://kittyhacker101.tk"
 
 
@@ -398,6 +1023,141 @@ hide = ["src", "target"]
 #dest = "http://localhost:8081"
 
 
+#[[proxy]]
+#location = "r#localhost/api/v(\\d+)/(?P<rest>.*)"
+
+# When using regex in the location, the path of dest can contain references to its capture groups ($1, ${1}, $name or ${name}). Use $$ for a literal $. Without regex, $ is always treated as literal text.
+# The filled in path is sent to the upstream instead of the part of the URL which wasn't matched by the location. Any query string is added to the end.
+#dest = "http://localhost:8081/${rest}?version=$1"
+
+
+#[[proxy]]
+#location = "localhost/socket"
+
+# Upstream servers listening on a Unix socket can be used by starting the dest with unix:. A path prefix can be added to requests by putting it after the socket's path.
+#dest = "unix:/run/app.sock:/api"
+
+
+#[[proxy]]
+#location = "localhost/php"
+
+# FastCGI application servers (such as PHP-FPM) can be used by starting the dest with fastcgi://, followed by either an address or a Unix socket.
+# Responses from FastCGI servers aren't cached, and their headers aren't rewritten.
+#dest = "fastcgi://127.0.0.1:9000"
+
+# script_root is the folder containing the scripts run by FastCGI servers. By default, the requested host's folder is used.
+#script_root = "/var/www/app"
+
+# script_index is the script used when a folder is requested.
+#script_index = "index.php"
+
+
+#[[proxy]]
+#location = "r#localhost/app.*"
+
+# Instead of a single dest, a list of upstream servers can be provided. Requests are spread between them using the strategy below.
+# The weight of an upstream is only used by the weighted strategy, and defaults to 1.
+#upstream = [{dest = "http://localhost:8081", weight = 2}, {dest = "http://localhost:8082"}]
+
+# strategy controls how an upstream server is picked for each request.
+# Supported values are round-robin, weighted, least-in-flight (the upstream handling the fewest requests), and ip-hash (each client always uses the same upstream).
+#strategy = "round-robin"
+
+# health enables periodic health checks for each upstream server. Upstreams which fail enough checks in a row stop receiving requests, until they pass enough checks in a row.
+# path is requested from each upstream every interval seconds, and is considered passing if the upstream responds with the expected status.
+# HTTPS upstreams are checked over TLS using the proxy's TLS settings. Upstreams specified by their IP address need tls_name to be set for this. The interval must be at least 1 second.
+# The health of each upstream is kept when the config is reloaded, as long as the upstream and its health check haven't changed.
+#health = {path = "/", interval = 10, status = 200, rise = 2, fall = 3}
+
+# max_fails controls how many requests in a row can fail (by being unable to connect, or by receiving a 5xx response) before an upstream is taken out of rotation. Setting this to 0 disables this.
+# After fail_timeout seconds, a single request is sent to the upstream to check if it has recovered.
+# If every upstream is out of rotation, requests will fail immediately with a 503 error.
+#max_fails = 5
+#fail_timeout = 30
+
+# cache allows responses from this proxy to be cached on the disk, following the Cache-Control, Expires and Vary headers sent by the upstream.
+# Only responses which explicitly allow caching are cached. The stale-while-revalidate and stale-if-error Cache-Control extensions are supported.
+# An X-Cache header is added to responses, showing whether they were served from the cache (HIT), from the cache while the upstream is unavailable or being revalidated (STALE), or from the upstream (MISS).
+#cache = false
+
+# forward controls which forwarding headers are sent to the upstream servers, so they can find out the client's IP address, and which host, port and protocol it used.
+# Supported values are x-forwarded-for, x-forwarded-proto, x-forwarded-host, x-forwarded-port, and forwarded (RFC 7239).
+#forward = ["x-forwarded-for", "x-forwarded-proto", "x-forwarded-host", "x-forwarded-port"]
+
+# retries controls how many times a failed request is retried. Each retry is sent to a different upstream server, and requests aren't retried once every upstream has been tried.
+# retry_on lists which failures are retried, which can be connect (unable to connect to the upstream), timeout, reset (the connection failed before a response was received), or a 5xx status code.
+# Only idempotent requests (GET, HEAD, OPTIONS, PUT, DELETE and TRACE) are retried, unless retry_non_idempotent is enabled.
+# Request bodies are kept in memory so they can be sent again, up to retry_buffer bytes. Requests with longer bodies aren't retried.
+#retries = 0
+#retry_on = ["connect", "reset"]
+#retry_non_idempotent = false
+#retry_buffer = 65536
+
+# These timeouts (in seconds) limit how long requests to the upstream servers can take, and default to the server's stream_timeout.
+# connect_timeout limits how long connecting to an upstream can take, first_byte_timeout limits how long the upstream can take to send its response headers, and read_timeout limits how long the upstream can go without sending any of the response body.
+# total_timeout limits how long the entire request can take, including retries and sending the response body. Setting it to 0 disables it.
+# Requests which time out before receiving a response get a 504 error, while responses which stop partway through are cut off.
+#connect_timeout = 20
+#first_byte_timeout = 20
+#read_timeout = 20
+#total_timeout = 0
+
+# max_body_size overrides the server's max_body_size for this location (in bytes). Setting it to 0 disables the limit for this location.
+#max_body_size = 0
+
+# buffer_uploads saves request bodies to a temporary file before the request is sent to the upstream, so that clients which upload slowly don't keep upstream connections busy.
+# This increases latency, and uses disk space up to max_body_size for each request being uploaded.
+#buffer_uploads = false
+
+# mirror sends a copy of requests to a secondary upstream server, which is useful for testing a new upstream with live traffic. The mirror's responses are ignored, and failures don't affect the original request.
+# mirror_percent controls what percentage of requests are copied. The status and latency of each mirrored request is logged.
+#mirror = "http://localhost:8090"
+#mirror_percent = 100
+
+# rewrite_redirects changes URLs in the Location, Content-Location and Refresh headers which point to an upstream server, so that they point to this proxy instead.
+# Absolute paths (like /login) also have the part of the path matched by the location added to them.
+#rewrite_redirects = true
+
+# redirects specifies extra rewrites for the Location, Content-Location and Refresh headers. URLs starting with the first value have it replaced with the second value.
+#redirects = [["http://internal.local/", "https://example.com/"]]
+
+# cookie_domains and cookie_paths rewrite the Domain and Path attributes of cookies set by the upstream server.
+# Domains must fully match the first value, while paths only need to start with it.
+#cookie_domains = [["internal.local", "example.com"]]
+#cookie_paths = [["/", "/app/"]]
+
+# tls_ca specifies a PEM file containing the certificate authorities trusted for HTTPS upstreams, replacing the default trust roots. This allows upstreams using a private CA to be proxied.
+#tls_ca = "ssl/upstream-ca.crt"
+
+# tls_cert and tls_key specify a client certificate to present to HTTPS upstreams which require one (mutual TLS). The key must be in pkcs8 format.
+#tls_cert = "ssl/client.crt"
+#tls_key = "ssl/client.pem"
+
+# tls_name overrides the server name used for SNI and certificate verification, which is useful if the upstream is specified by its IP address.
+#tls_name = "backend.internal"
+
+# tls_insecure disables certificate verification for HTTPS upstreams. This should only be used for development.
+#tls_insecure = false
+
+# proxy_protocol sends a PROXY protocol header with the client's address to upstream servers when connecting. This can be 1 or 2 for the protocol's version, or 0 to disable it.
+# The upstream servers must be expecting the header. Headers aren't sent to Unix socket, FastCGI or mirror upstreams.
+#proxy_protocol = 0
+
+
+#[[canary]] # Canary routing for reverse proxies
+# The proxy location that this canary takes a slice of traffic from. This must be the same as the location of a [[proxy]] section.
+#location = "r#localhost/app.*"
+
+# Requests are sent to the canary if they have the header or cookie, or if the client's IP falls within the percentage. Clients always get the same result from the percentage.
+# The header and cookie can either require a specific value, or only require the header or cookie to be present.
+#header = "X-Canary: 1"
+#cookie = "canary=1"
+#percent = 5
+
+# Every other [[proxy]] option can be used here, and only applies to the canary.
+#dest = "http://localhost:8083"
+
+
 #[[redir]] # HTTP redirects
 # The url (without the protocol) that this redirect affects. This field supports regex.
 #location = "localhost/redir"
@@ -411,6 +1171,46 @@ hide = ["src", "target"]
 #dest = "https://google.com"
 
 
+#[[redir]]
+# Like [[proxy]], the destination can contain references to the location's capture groups, which replaces adding the unmatched part of the URL to it.
+#location = "r#localhost/user/(\\w+)"
+#dest = "https://example.com/profile?name=$1"
+
+
+#[[headers]] # Request and response header rules
+# The url (without the protocol) that this rule affects. Plain locations only affect that exact url, use regex to affect more than one url.
+# Every matching rule is applied, in the order they are listed.
+#location = "localhost/app"
+
+# request_remove, request_set and request_add change the headers of requests sent to upstream servers by [[proxy]] locations.
+# response_remove, response_set and response_add change the headers of every response sent to the client, including the headers set by the protect and hsts options.
+# Headers are removed first, then set (replacing any existing values), and then added (keeping any existing values).
+# Values can contain the variables $client_ip, $host, $path, $query, $method and $scheme. Use $$ for a literal $.
+#request_remove = ["Cookie"]
+#request_set = [["X-Real-IP", "$client_ip"]]
+#request_add = [["X-Original-URL", "$scheme://$host$path"]]
+#response_remove = ["X-XSS-Protection"]
+#response_set = [["Content-Security-Policy", "default-src 'self'"]]
+#response_add = [["X-Served-By", "KatWebX"]]
+
+
+#[[passthrough]] # TLS passthrough
+# Connections to the TLS listener for this server name (SNI) are passed through to the destination without being decrypted, so the destination handles TLS itself.
+# All other server names are handled by KatWebX as normal. Enabling passthrough requires a restart, but the server names and destinations can be changed by reloading the config.
+#name = "secure.local"
+#dest = "127.0.0.1:8443"
+
+# proxy_protocol sends a PROXY protocol header (version 1 or 2) with the client's address to the destination. Setting this to 0 disables it.
+#proxy_protocol = 0
+
+
+#[[stream]] # TCP forwarding
+# Every connection to the listen address is forwarded to the destination. This can be used for services which don't use HTTP. Adding or changing streams requires a restart.
+#listen = "[::]:2222"
+#dest = "127.0.0.1:22"
+#proxy_protocol = 0
+
+
 #[[auth]] # HTTP basic authentication
 # The url (without the protocol) that this affects. This field must be regex.
 #location = "r#localhost/demopass.*"
@@ -418,4 +1218,24 @@ hide = ["src", "target"]
 # The username and password required to get access to the resource, split by a ":" character.
 # Note that brute forcing logins isn't very difficult to do, so make sure you use a complex username and password.
 #login = "admin:passwd"
-"##;
\ No newline at end of file
+"##;
+#[cfg(test)]
+mod tests {
+	use super::check_captures;
+
+	#[test]
+	fn regex_captures() {
+		assert_eq!(check_captures("r#/user/(?P<id>[0-9]+)", "/profile/$id"), Ok(true));
+		assert_eq!(check_captures("r#/a/(.*)", "/b/${1}"), Ok(true));
+		assert_eq!(check_captures("r#/a/(.*)", "/b/"), Ok(false));
+		assert_eq!(check_captures("r#/a/(.*)", "/b/$$"), Ok(false));
+		assert!(check_captures("r#/a/(.*)", "/b/$2").is_err());
+		assert!(check_captures("r#/a/(.*)", "/b/$name").is_err());
+	}
+
+	#[test]
+	fn literal_dollar() {
+		assert_eq!(check_captures("/shop", "https://example.com/$sale"), Ok(false));
+		assert_eq!(check_captures("/shop", "https://example.com/${1}"), Ok(false));
+	}
+}
@@ -0,0 +1,243 @@
+// Headers.rs handles the [[headers]] rules, which change the headers of requests sent to upstream servers and responses sent to clients.
+extern crate actix_web;
+extern crate regex;
+extern crate percent_encoding;
+use actix_web::{dev::RequestHead, http::header::{self, HeaderMap, HeaderName, HeaderValue}};
+use regex::Regex;
+use percent_encoding::percent_decode;
+use trim_port;
+
+// The variables which can be used in header values. They are written as $name or ${name}, and $$ is used for a literal $.
+const VARS: [&str; 6] = ["client_ip", "host", "path", "query", "method", "scheme"];
+
+// Part of a header value, which is either text or a variable that is filled in for each request.
+enum Part {
+	Text(String),
+	Var(usize),
+}
+
+// A header value which may contain variables.
+struct Template(Vec<Part>);
+
+impl Template {
+	fn parse(value: &str) -> Result<Self, String> {
+		let mut parts = Vec::new();
+		let mut text = String::new();
+		let mut rest = value;
+
+		while let Some(i) = rest.find('$') {
+			text.push_str(&rest[..i]);
+			rest = &rest[i+1..];
+			if rest.starts_with('$') {
+				text.push('$');
+				rest = &rest[1..];
+				continue
+			}
+
+			let (name, len) = if rest.starts_with('{') {
+				let end = rest.find('}').ok_or_else(|| ["Unclosed variable in header value \"", value, "\"!"].concat())?;
+				(&rest[1..end], end + 1)
+			} else {
+				let end = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or_else(|| rest.len());
+				(&rest[..end], end)
+			};
+			let var = VARS.iter().position(|v| *v == name).ok_or_else(|| ["Unknown variable \"$", name, "\" in header value \"", value, "\"!"].concat())?;
+
+			if !text.is_empty() {
+				parts.push(Part::Text(text));
+				text = String::new();
+			}
+			parts.push(Part::Var(var));
+			rest = &rest[len..];
+		}
+		text.push_str(rest);
+		if !text.is_empty() {
+			parts.push(Part::Text(text));
+		}
+
+		// Checking a copy of the value with no variables filled in catches invalid characters in the text parts.
+		let check: String = parts.iter().map(|p| match p {Part::Text(t) => t.as_str(), Part::Var(_) => ""}).collect();
+		HeaderValue::from_str(&check).map_err(|_| ["Invalid header value \"", value, "\"!"].concat())?;
+		Ok(Self(parts))
+	}
+
+	// Fill in the variables, returning None if the result isn't a valid header value.
+	fn render(&self, vars: &Vars) -> Option<HeaderValue> {
+		let value: String = self.0.iter().map(|p| match p {
+			Part::Text(t) => t.as_str(),
+			Part::Var(v) => vars.get(*v),
+		}).collect();
+		HeaderValue::from_str(&value).ok()
+	}
+}
+
+// Information about a request, which is used for matching rules and filling in variables.
+#[derive(Clone)]
+pub struct Vars {
+	client_ip: String,
+	host: String,
+	path: String,
+	query: String,
+	method: String,
+	scheme: String,
+	location: String,
+}
+
+impl Vars {
+	pub fn new(head: &RequestHead, client_ip: &str, https: bool) -> Self {
+		let host = head.headers.get(header::HOST).and_then(|h| h.to_str().ok()).or_else(|| head.uri.host()).unwrap_or("").to_owned();
+		let location = [trim_port(&host), &percent_decode(head.uri.path().as_bytes()).decode_utf8_lossy()].concat();
+		Self {
+			client_ip: client_ip.to_owned(),
+			path: head.uri.path().to_owned(),
+			query: head.uri.query().unwrap_or("").to_owned(),
+			method: head.method.as_str().to_owned(),
+			scheme: if https {"https".to_owned()} else {"http".to_owned()},
+			host,
+			location,
+		}
+	}
+
+	fn get(&self, var: usize) -> &str {
+		match VARS[var] {
+			"client_ip" => &self.client_ip,
+			"host" => &self.host,
+			"path" => &self.path,
+			"query" => &self.query,
+			"method" => &self.method,
+			_ => &self.scheme,
+		}
+	}
+}
+
+// A set of header changes. Headers are removed first, then set (replacing existing values), and then added (keeping existing values).
+pub struct Actions {
+	remove: Vec<HeaderName>,
+	set: Vec<(HeaderName, Template)>,
+	add: Vec<(HeaderName, Template)>,
+}
+
+impl Actions {
+	pub fn new(remove: &[String], set: &[(String, String)], add: &[(String, String)]) -> Result<Self, String> {
+		let name = |n: &str| HeaderName::from_bytes(n.as_bytes()).map_err(|_| ["Invalid header name \"", n, "\"!"].concat());
+		let pair = |(n, v): &(String, String)| -> Result<(HeaderName, Template), String> {Ok((name(n)?, Template::parse(v)?))};
+		Ok(Self {
+			remove: remove.iter().map(|n| name(n)).collect::<Result<_, String>>()?,
+			set: set.iter().map(pair).collect::<Result<_, String>>()?,
+			add: add.iter().map(pair).collect::<Result<_, String>>()?,
+		})
+	}
+
+	fn apply(&self, headers: &mut HeaderMap, vars: &Vars) {
+		for key in &self.remove {
+			headers.remove(key);
+		}
+		for (key, value) in &self.set {
+			match value.render(vars) {
+				Some(v) => {headers.insert(key.to_owned(), v);},
+				None => {headers.remove(key);},
+			}
+		}
+		for (key, value) in &self.add {
+			if let Some(v) = value.render(vars) {
+				headers.append(key.to_owned(), v);
+			}
+		}
+	}
+}
+
+// A rule which changes the headers of requests and responses for a location. Locations starting with r# are regex, other locations only match the exact URL (host+path).
+pub struct Rule {
+	location: String,
+	regex: Option<Regex>,
+	request: Actions,
+	response: Actions,
+}
+
+impl Rule {
+	pub fn new(location: &str, request: Actions, response: Actions) -> Result<Self, String> {
+		let regex = if location.starts_with("r#") {
+			Some(Regex::new(&location[2..]).map_err(|err| err.to_string())?)
+		} else {
+			None
+		};
+		Ok(Self { location: location.to_owned(), regex, request, response })
+	}
+
+	fn matches(&self, vars: &Vars) -> bool {
+		match self.regex.as_ref() {
+			Some(r) => r.is_match(&vars.location),
+			None => vars.location == self.location,
+		}
+	}
+}
+
+// The header rules from the config. Every matching rule is applied, in the order they were configured.
+pub struct Rules(pub Vec<Rule>);
+
+impl Rules {
+	pub fn is_empty(&self) -> bool {
+		self.0.is_empty()
+	}
+
+	// Change the headers of a request being sent to an upstream server.
+	pub fn request(&self, headers: &mut HeaderMap, vars: &Vars) {
+		for rule in self.0.iter().filter(|r| r.matches(vars)) {
+			rule.request.apply(headers, vars)
+		}
+	}
+
+	// Change the headers of a response being sent to the client.
+	pub fn response(&self, headers: &mut HeaderMap, vars: &Vars) {
+		for rule in self.0.iter().filter(|r| r.matches(vars)) {
+			rule.response.apply(headers, vars)
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Actions, Rule, Template, Vars};
+
+	fn vars() -> Vars {
+		Vars {
+			client_ip: "192.0.2.1".to_owned(),
+			host: "example.com".to_owned(),
+			path: "/a".to_owned(),
+			query: "b=c".to_owned(),
+			method: "GET".to_owned(),
+			scheme: "https".to_owned(),
+			location: "example.com/a".to_owned(),
+		}
+	}
+
+	fn render(value: &str) -> String {
+		let template = Template::parse(value).expect("template should be valid");
+		template.render(&vars()).expect("rendered value should be valid").to_str().unwrap_or("").to_owned()
+	}
+
+	#[test]
+	fn variables() {
+		assert_eq!(render("no variables"), "no variables");
+		assert_eq!(render("$scheme://$host$path?$query"), "https://example.com/a?b=c");
+		assert_eq!(render("${method}_${client_ip}"), "GET_192.0.2.1");
+		assert_eq!(render("$$host costs $$5"), "$host costs $5");
+	}
+
+	#[test]
+	fn invalid() {
+		assert!(Template::parse("$unknown").is_err());
+		assert!(Template::parse("${host").is_err());
+		assert!(Template::parse("bad\nvalue").is_err());
+	}
+
+	#[test]
+	fn locations() {
+		let rule = |location: &str| Rule::new(location, Actions::new(&[], &[], &[]).expect("actions should be valid"), Actions::new(&[], &[], &[]).expect("actions should be valid")).expect("rule should be valid");
+		assert!(rule("example.com/a").matches(&vars()));
+		assert!(!rule("example.com/").matches(&vars()));
+		assert!(!rule("example.com/a/b").matches(&vars()));
+		assert!(!rule("example.com/ab").matches(&Vars { location: "example.com/abc".to_owned(), ..vars() }));
+		assert!(rule("r#example\\.com/.*").matches(&vars()));
+	}
+}
@@ -32,16 +32,28 @@ extern crate chrono;
 extern crate percent_encoding;
 extern crate exitcode;
 mod stream;
-use stream::{trim_prefix, trim_suffix, trim_host, trim_port, open_meta};
+use stream::{trim_prefix, trim_suffix, trim_host, trim_port, open_meta, Ranges};
 mod ui;
+mod websocket;
+mod upstream;
+mod cache;
+use cache::Entry;
+use upstream::BodyTimeout;
+mod rewrite;
+use rewrite::Rewriter;
+mod cgi;
+mod mirror;
+mod headers;
+mod upload;
+mod tcp;
 mod config;
-use config::Config;
+use config::{Config, Proxy};
 mod certs;
 use actix::System;
-use futures::Future;
+use futures::{Future, Stream, future, future::Loop, stream};
 use actix_http::body::BodyStream;
-use actix_web::{web, web::Payload, Either, HttpServer, client::ClientBuilder, App, http::{header, header::{HeaderValue, HeaderMap}, Method, ContentEncoding, StatusCode}, HttpRequest, HttpResponse, Error, middleware::BodyEncoding, dev::{Body, ConnectionInfo}};
-use std::{env, process, fs, string::String, path::Path, time::Duration, sync::{Arc, RwLock, RwLockReadGuard}, ffi::OsStr, thread};
+use actix_web::{web::Payload, Either, client::{ClientRequest, ConnectError, SendRequestError}, error::PayloadError, http::{header, header::{HeaderMap, HeaderName, HeaderValue}, Method, ContentEncoding, StatusCode}, HttpRequest, HttpResponse, Error, middleware::BodyEncoding, dev::{Body, ConnectionInfo, RequestHead}};
+use std::{env, process, fs, io, string::String, sync::{Arc, RwLock, RwLockReadGuard}, ffi::OsStr, net, thread, time::{Duration, Instant}};
 use bytes::Bytes;
 use chrono::Local;
 use percent_encoding::{percent_decode};
@@ -65,40 +77,213 @@ fn rc(lock: &CONFM) -> RwLockReadGuard<Config> {
 	})
 }
 
-/* Reverse proxy a request, passing through any compression.
-Hop-by-hop headers are removed, to allow connection reuse. */
-fn proxy_request(path: &str, method: Method, headers: &HeaderMap, body: Payload, client_ip: &str, c: &Config) -> Box<Future<Item=HttpResponse, Error=Error>> {
-	let mut req = ClientBuilder::new().timeout(Duration::from_secs(c.stream_timeout as u64))
-		.max_redirects(5).finish().request(method, path).no_decompress();
+// Reverse proxy a request, logging it once the status of the response is known. Errors are turned into their responses, so that they're logged with their real status.
+fn proxy_request(path: &str, proxy: &Arc<Proxy>, req: &HttpRequest, body: Payload, client_ip: &str, c: &Config) -> Box<Future<Item=HttpResponse, Error=Error>> {
+	let (log_req, log_format) = (req.to_owned(), c.log_format.to_owned());
+	let label = if proxy.canary {"WebProxyCanary"} else {"WebProxy"};
+	Box::new(proxy_upstream(path, proxy, req.head(), body, client_ip, req.app_config().secure(), c).then(move |res| -> Result<HttpResponse, Error> {
+		let resp = res.unwrap_or_else(HttpResponse::from);
+		log_data(&log_format, resp.status().as_u16(), label, &log_req, &log_req.connection_info(), None);
+		Ok(resp)
+	}))
+}
 
-	for (key, value) in headers.iter() {
+/* Send a request to an upstream picked from the proxy's pool, passing through any compression.
+Hop-by-hop headers are removed, to allow connection reuse. Websocket upgrades are handed off to the websocket proxy. */
+fn proxy_upstream(path: &str, proxy: &Arc<Proxy>, head: &RequestHead, body: Payload, client_ip: &str, https: bool, c: &Config) -> Box<Future<Item=HttpResponse, Error=Error>> {
+	// Requests which are larger than the body size limit are rejected before anything is sent to the upstream.
+	let limit = proxy.max_body_size.unwrap_or(c.max_body_size);
+	if upload::too_large(&head.headers, limit) {
+		return Box::new(future::ok(body_too_large(c.smaller_default)))
+	}
+
+	// The public URL of the location is needed to rewrite URLs in the response. The prefix is the part of the path which was matched by the location, rather than sent to the upstream.
+	let host_port = head.headers.get(header::HOST).and_then(|h| h.to_str().ok()).unwrap_or("");
+	let prefix = location_prefix(head, path);
+	let base = [if https {"https://"} else {"http://"}, host_port, &prefix].concat();
+
+	// If caching is enabled, fresh responses are served directly from the cache.
+	let cacheable = proxy.cache && cache::request_cacheable(&head.method, &head.headers);
+	let cache_url = cache::cache_url(&proxy.location, &base, path);
+	let cached = if cacheable {c.cache.lookup(&cache_url, &head.headers)} else {None};
+	if let Some(entry) = cached.as_ref().filter(|e| e.is_fresh()) {
+		return Box::new(future::ok(entry.response("HIT")))
+	}
+
+	let start = Instant::now();
+	let upstream = match proxy.pool.pick(client_ip) {
+		Some(u) => u,
+		None => {
+			if let Some(entry) = cached.filter(Entry::stale_if_error) {
+				return Box::new(future::ok(entry.response("STALE")))
+			}
+			println!("[Warn]: Unable to proxy request for {}, as all of its upstream servers are unavailable.", proxy.location);
+			return Box::new(future::ok(ui::http_error(StatusCode::SERVICE_UNAVAILABLE, "503 Service Unavailable", "The server was acting as a proxy and has no upstream servers available to handle the request.", c.smaller_default)))
+		},
+	};
+	// FastCGI upstreams run a script from the host's folder, instead of being sent an HTTP request.
+	if let Some(addr) = upstream::fastcgi_addr(upstream.dest()) {
+		let root = proxy.script_root.to_owned().unwrap_or_else(|| {
+			let host = head.headers.get(header::HOST).and_then(|h| h.to_str().ok()).unwrap_or("");
+			env::current_dir().unwrap_or_default().join(c.host_folder(host)).to_string_lossy().into_owned()
+		});
+		let script = match cgi::find_script(&root, &location_prefix(head, path), path.split('?').next().unwrap_or(""), &proxy.script_index) {
+			Some(s) => s,
+			None => return Box::new(future::ok(ui::http_error(StatusCode::NOT_FOUND, "404 Not Found", &["The resource ", head.uri.path(), " could not be found."].concat(), c.smaller_default))),
+		};
+
+		return Box::new(cgi::fastcgi(addr, cgi::params(head, &script, client_ip, https, Some(&c.header_rules)), upload::limit(body, limit).0, proxy.timeouts.read, c.smaller_default).then(move |res| {
+			if res.is_ok() {upstream.success()} else {upstream.failure()}
+			res
+		}))
+	}
+
+	let (socket, url) = upstream_url(upstream.dest(), path);
+	let forwarded = forwarding_headers(head, client_ip, https, &proxy.forward, c.trusted_proxies.iter().any(|p| p == client_ip));
+
+	let client = proxy.client(socket, proxy.timeouts.first_byte, head.peer_addr);
+
+	// The headers are the same for every upstream, so they only need to be built once, even if the request is retried.
+	let mut headers = HeaderMap::new();
+	for (key, value) in head.headers.iter() {
 		match key.as_str() {
-			"connection" | "proxy-connection" | "host" | "keep-alive" | "proxy-authenticate" | "proxy-authorization" | "transfer-encoding" | "upgrade" => (),
-			"x-forwarded-for" => {
-				req = req.set_header("X-Forwarded-For", [value.to_str().unwrap_or("127.0.0.1"), ", ", client_ip].concat());
-				continue
-			},
-			_ => {
-				req = req.header(key.to_owned(), value.to_owned());
-				continue
-			},
+			"connection" | "proxy-connection" | "host" | "keep-alive" | "proxy-authenticate" | "proxy-authorization" | "transfer-encoding" | "upgrade" | "expect" => (),
+			"x-forwarded-for" | "x-forwarded-proto" | "x-forwarded-host" | "x-forwarded-port" | "forwarded" => (),
+			_ => headers.append(key.to_owned(), value.to_owned()),
 		};
 	}
-	req = req.set_header_if_none(header::USER_AGENT, "KatWebX-Proxy")
-		.set_header_if_none("X-Forwarded-For", client_ip)
-		.set_header_if_none(header::ACCEPT_ENCODING, "none");
+	for (key, value) in forwarded {
+		if let (Ok(k), Ok(v)) = (HeaderName::from_bytes(key.as_bytes()), HeaderValue::from_str(&value)) {
+			headers.insert(k, v);
+		}
+	}
+	if !headers.contains_key(header::USER_AGENT) {
+		headers.insert(header::USER_AGENT, HeaderValue::from_static("KatWebX-Proxy"));
+	}
+	if !headers.contains_key(header::ACCEPT_ENCODING) {
+		headers.insert(header::ACCEPT_ENCODING, HeaderValue::from_static("none"));
+	}
+	if !c.header_rules.is_empty() {
+		c.header_rules.request(&mut headers, &headers::Vars::new(head, client_ip, https));
+	}
+
+	// Websocket connections are sent the same headers, but the rest of the connection is handled by websocket.rs.
+	if websocket::is_upgrade(&head.headers) {
+		return websocket::proxy(&client, &url, head, body, headers, upstream, c)
+	}
+
+	let (body, exceeded) = upload::limit(body, limit);
+
+	// Stale responses which allow it are served while a fresh copy is fetched in the background.
+	if let Some(entry) = cached.as_ref().filter(|e| e.stale_while_revalidate()) {
+		cache::revalidate(&client, c.cache.to_owned(), entry, &url, headers, head.headers.to_owned());
+		return Box::new(future::ok(entry.response("STALE")))
+	}
+
+	let auto = if proxy.rewrite_redirects {proxy.pool.dests()} else {Vec::new()};
+	let rewriter = Rewriter::new(&proxy.redirects, &auto, &base, &prefix, &proxy.cookie_domains, &proxy.cookie_paths);
+
+	// A copy of the request is sent to the mirror if the request is picked by the mirror's sampling percentage. The request doesn't wait for the mirror.
+	let mirror = proxy.mirror.as_ref().filter(|m| m.sample()).map(|m| {
+		let (mirror_socket, mirror_url) = upstream_url(&m.dest, path);
+		let log = ["[", host_port, head.uri.path(), "] : ", client_ip].concat();
+		(proxy.client(mirror_socket, proxy.timeouts.first_byte, None), mirror_url, log)
+	});
+	let (mirror_method, mirror_headers, log_format) = (head.method.to_owned(), headers.to_owned(), c.log_format.to_owned());
+
+	let (smaller_default, debug) = (c.smaller_default, c.proxy_debug);
+	let store = if cacheable && head.method == Method::GET {Some((c.cache.to_owned(), cache_url, head.headers.to_owned()))} else {None};
 
-	let smaller_default = c.smaller_default;
+	// If uploads are buffered, the entire body is saved to the disk before anything is sent to the upstream or the mirror.
+	let body: Box<Future<Item=upload::Upload, Error=PayloadError>> = if proxy.buffer_uploads {upload::spool(body, c.max_streaming_len)} else {Box::new(future::ok(body))};
 
-	Box::new(req.send_stream(body).map_err(move |_err| {
-		// The only SendRequestError that could be caused by a user would be InvalidUrl, but we already do URL checking. All possible SendRequestErrors can't be caused by a client issue, only a server-side one.
-		Error::from(ui::http_error(StatusCode::BAD_GATEWAY, "502 Bad Gateway", "The server was acting as a proxy and received an invalid response from the upstream server.", smaller_default))
-		//Error::from(_err) // This should only be uncommented when debugging potential issues with KatWebX. In the future, KatWebX will implement more detailed error messages.
-	}).map(|resp| {
-		HttpResponse::Ok()
+	// Retries need the request body to be sent again, so it's kept in memory (up to the retry buffer size) if the request can be retried.
+	let retry = proxy.retry.to_owned().filter(|r| r.allows_method(&head.method));
+	let retry_buffer = retry.as_ref().map(|r| r.buffer);
+	let buffered = body.and_then(move |body| -> Box<Future<Item=(Vec<Bytes>, Option<mirror::Tee>), Error=PayloadError>> {
+		let body = match mirror {
+			Some((client, url, log)) => mirror::send(&client, &url, mirror_method, &mirror_headers, body, &log_format, log),
+			None => mirror::Tee::new(body),
+		};
+		match retry_buffer {
+			Some(limit) => buffer_body(body, limit),
+			None => Box::new(future::ok((Vec::new(), Some(body)))),
+		}
+	});
+
+	// Bodies which were cut off for being larger than the limit get a 413 error, instead of being treated as an upstream failure.
+	let buffer_exceeded = exceeded.to_owned();
+	let (loop_proxy, path, method, client_ip, source, timeouts) = (proxy.to_owned(), path.to_owned(), head.method.to_owned(), client_ip.to_owned(), head.peer_addr, proxy.timeouts);
+	Box::new(buffered.map_err(move |err| {
+		if buffer_exceeded.get() {Error::from(body_too_large(smaller_default))} else {Error::from(err)}
+	}).and_then(move |(chunks, rest)| {
+		// Requests can only be retried if their entire body was buffered.
+		let attempts = match (&retry, &rest) {
+			(Some(r), None) => r.attempts,
+			_ => 0,
+		};
+
+		future::loop_fn((upstream, Vec::new(), attempts, rest), move |(upstream, mut tried, attempts, rest)| {
+			let body: Box<Stream<Item=Bytes, Error=PayloadError>> = match rest {
+				Some(rest) => Box::new(stream::iter_ok::<_, PayloadError>(chunks.to_owned()).chain(rest)),
+				None => Box::new(stream::iter_ok::<_, PayloadError>(chunks.to_owned())),
+			};
+
+			let (proxy, retry, client_ip) = (loop_proxy.to_owned(), retry.to_owned(), client_ip.to_owned());
+			upstream_request(&proxy, upstream.dest(), &path, &method, &headers, source, timeouts.first_byte(start)).send_stream(body).then(move |res| -> Result<Loop<_, _>, Error> {
+				let retryable = attempts > 0 && retry.map_or(false, |r| match &res {
+					Ok(resp) => r.on_status(resp.status()),
+					Err(err) => r.on_error(err),
+				});
+
+				// Each retry is sent to an upstream that hasn't been tried yet. FastCGI upstreams can't be used for retries, as they don't handle HTTP requests.
+				if retryable {
+					tried.push(upstream.dest().to_owned());
+					if let Some(next) = proxy.pool.pick_excluding(&client_ip, &tried).filter(|u| upstream::fastcgi_addr(u.dest()).is_none()) {
+						upstream.failure();
+						println!("[Warn]: Proxied request to {} failed, retrying it on {}.", upstream.dest(), next.dest());
+						return Ok(Loop::Continue((next, tried, attempts - 1, None)))
+					}
+				}
+				Ok(Loop::Break((upstream, res)))
+			})
+		})
+	}).and_then(move |(upstream, res)| {
+		let resp = match res {
+			Ok(r) => r,
+			Err(_) if exceeded.get() => return Ok(body_too_large(smaller_default)),
+			Err(err) => {
+				// The only SendRequestError that could be caused by a user would be InvalidUrl, but we already do URL checking. All possible SendRequestErrors can't be caused by a client issue, only a server-side one.
+				upstream.failure();
+				if let Some(entry) = cached.filter(Entry::stale_if_error) {
+					return Ok(entry.response("STALE"))
+				}
+				return Err(Error::from(proxy_error(&err, upstream.dest(), smaller_default, debug)))
+			},
+		};
+
+		if resp.status().is_server_error() {
+			upstream.failure();
+			if let Some(entry) = cached.as_ref().filter(|e| e.stale_if_error()) {
+				return Ok(entry.response("STALE"))
+			}
+		} else {
+			upstream.success()
+		}
+
+		// Save the response into the cache while it is being sent to the client, if the upstream allows it to be cached.
+		let headers = rewriter.apply(resp.headers());
+		let meta = store.as_ref().and_then(|(_, url, _)| cache::response_meta(url, resp.status(), &headers));
+		if let (None, Some(entry), Some((cache, _, _))) = (&meta, &cached, &store) {
+			cache.remove(entry);
+		}
+		let is_cached = store.is_some();
+		let store = store.and_then(|(cache, url, headers)| Some((cache, url, headers, meta?)));
+
+		Ok(HttpResponse::Ok()
 			.status(resp.status())
 			.if_true(true, |req| {
-				for (key, value) in resp.headers().iter() {
+				for (key, value) in headers.iter() {
 					match key.as_str() {
 						"connection" | "proxy-connection" | "host" | "keep-alive" | "proxy-authenticate" | "proxy-authorization" | "transfer-encoding" | "upgrade" => (),
 						"content-encoding" => {req.header(key.to_owned(), value.to_owned()); req.encoding(ContentEncoding::Identity);}, // Make sure compressed data doesn't get recompressed.
@@ -106,10 +291,137 @@ fn proxy_request(path: &str, method: Method, headers: &HeaderMap, body: Payload,
 					}
 				}
 			})
-			.streaming(resp)
+			.if_true(is_cached, |req| {
+				req.header("X-Cache", "MISS");
+			})
+			.streaming(cache::Tee::new(BodyTimeout::new(resp, timeouts, start), store).map(move |chunk| {
+				// The upstream is counted as handling the request until the entire body has been proxied.
+				let _ = &upstream;
+				chunk
+			})))
+	}))
+}
+
+// Build a request to an upstream server. The headers should have already been filtered, and the source is the client's address for PROXY protocol headers.
+fn upstream_request(proxy: &Proxy, dest: &str, path: &str, method: &Method, headers: &HeaderMap, source: Option<net::SocketAddr>, timeout: Duration) -> ClientRequest {
+	let (socket, url) = upstream_url(dest, path);
+	let mut req = proxy.client(socket, timeout, source).request(method.to_owned(), url.as_str()).no_decompress();
+	for (key, value) in headers.iter() {
+		req = req.header(key.to_owned(), value.to_owned());
+	}
+	req
+}
+
+// Generate the error page sent when a request body is larger than the body size limit.
+fn body_too_large(smaller: bool) -> HttpResponse {
+	ui::http_error(StatusCode::PAYLOAD_TOO_LARGE, "413 Payload Too Large", "The request body is larger than the server allows.", smaller)
+}
+
+// Find the body size limit for a request, using the same routing as handle. This lets uploads be rejected before the client sends the body.
+fn body_limit(head: &RequestHead, conf: &Config) -> u64 {
+	let rawpath = percent_decode(head.uri.path().as_bytes()).decode_utf8_lossy();
+	let host = head.headers.get(header::HOST).and_then(|h| h.to_str().ok()).or_else(|| head.uri.host()).unwrap_or("");
+	let auth = head.headers.get(header::AUTHORIZATION).unwrap_or(&BLANKHEAD).to_str().unwrap_or("");
+	let client_ip = head.peer_addr.map_or_else(|| "127.0.0.1".to_owned(), |a| a.ip().to_string());
+
+	let (_, kind, fp) = conf.handle_path(&rawpath, host, auth, &head.headers, &client_ip);
+	match fp.filter(|_| kind == "proxy").and_then(|loc| conf.get_proxy(&loc)) {
+		Some(proxy) => proxy.max_body_size.unwrap_or(conf.max_body_size),
+		None => conf.max_body_size,
+	}
+}
+
+// Read up to limit bytes of a request body into memory, so that it can be sent again if the request is retried. If the body is longer than the limit, the rest of it is returned as a stream.
+fn buffer_body(body: mirror::Tee, limit: usize) -> Box<Future<Item=(Vec<Bytes>, Option<mirror::Tee>), Error=PayloadError>> {
+	Box::new(future::loop_fn((body, Vec::new(), 0), move |(body, mut chunks, size): (mirror::Tee, Vec<Bytes>, usize)| {
+		body.into_future().map_err(|(err, _)| err).map(move |(chunk, body)| {
+			match chunk {
+				Some(c) => {
+					let size = size + c.len();
+					chunks.push(c);
+					if size > limit {
+						return Loop::Break((chunks, Some(body)))
+					}
+					Loop::Continue((body, chunks, size))
+				},
+				None => Loop::Break((chunks, None)),
+			}
+		})
 	}))
 }
 
+// Get the URL of a request to an upstream server, along with the Unix socket to connect to. Requests to Unix sockets use localhost as their host, as the connection is made to the socket no matter which host is used.
+fn upstream_url<'a>(dest: &'a str, path: &str) -> (Option<&'a str>, String) {
+	match upstream::unix_socket(dest) {
+		Some((socket, prefix)) => (Some(socket), ["http://localhost", prefix, path].concat()),
+		None => (None, [dest, path].concat()),
+	}
+}
+
+// Get the part of a proxied request's path which was matched by the proxy location, rather than being sent to the upstream.
+fn location_prefix(head: &RequestHead, path: &str) -> String {
+	let req_path = percent_decode(head.uri.path().as_bytes()).decode_utf8_lossy();
+	let suffix = path.split('?').next().unwrap_or("");
+	if req_path.ends_with(suffix) {
+		return req_path[..req_path.len()-suffix.len()].to_owned()
+	}
+	String::new()
+}
+
+/* Generate the forwarding headers sent to an upstream server, limited to the ones enabled for the proxy.
+Incoming forwarding headers are always removed from the request. If the client is a trusted proxy, the values it sent are extended or kept instead of being replaced. */
+fn forwarding_headers(head: &RequestHead, client_ip: &str, https: bool, enabled: &[String], trusted: bool) -> Vec<(String, String)> {
+	let incoming = |name: &str| if trusted {head.headers.get(name).and_then(|h| h.to_str().ok())} else {None};
+	let proto = if https {"https"} else {"http"};
+	let host_port = head.headers.get(header::HOST).and_then(|h| h.to_str().ok()).or_else(|| head.uri.host()).unwrap_or("");
+	let host = trim_port(host_port);
+	let port = match trim_prefix(":", trim_host(host_port)) {
+		"" => if https {"443"} else {"80"},
+		p => p,
+	};
+
+	let mut headers = Vec::new();
+	for name in enabled {
+		let value = match name.as_str() {
+			"x-forwarded-for" => incoming(name).map_or_else(|| client_ip.to_owned(), |v| [v, ", ", client_ip].concat()),
+			"x-forwarded-proto" => incoming(name).unwrap_or(proto).to_owned(),
+			"x-forwarded-host" => incoming(name).unwrap_or(host).to_owned(),
+			"x-forwarded-port" => incoming(name).unwrap_or(port).to_owned(),
+			"forwarded" => {
+				// RFC 7239 requires IPv6 addresses to be quoted and wrapped in brackets.
+				let node = if client_ip.contains(':') {["\"[", client_ip, "]\""].concat()} else {client_ip.to_owned()};
+				let new = ["for=", &node, ";host=\"", host_port, "\";proto=", proto].concat();
+				incoming(name).map_or_else(|| new.to_owned(), |v| [v, ", ", &new].concat())
+			},
+			_ => continue,
+		};
+		headers.push((name.to_owned(), value));
+	}
+	headers
+}
+
+/* Turn an error from sending a proxied request into an error page, and log it.
+Timeouts result in a 504, while being unable to connect or receiving an invalid response results in a 502. If proxy_debug is enabled, the error is included in the page. */
+fn proxy_error(err: &SendRequestError, upstream: &str, smaller: bool, debug: bool) -> HttpResponse {
+	let (status, title, kind, reason) = match err {
+		SendRequestError::Timeout | SendRequestError::Connect(ConnectError::Timeout) =>
+			(StatusCode::GATEWAY_TIMEOUT, "504 Gateway Timeout", "timeout", "The server was acting as a proxy and did not receive a timely response from the upstream server."),
+		SendRequestError::Connect(ConnectError::Resolver(_)) | SendRequestError::Connect(ConnectError::NoRecords) =>
+			(StatusCode::BAD_GATEWAY, "502 Bad Gateway", "dns", "The server was acting as a proxy and was unable to resolve the upstream server's address."),
+		SendRequestError::Connect(ConnectError::Io(e)) if e.kind() == io::ErrorKind::ConnectionRefused =>
+			(StatusCode::BAD_GATEWAY, "502 Bad Gateway", "refused", "The server was acting as a proxy and the upstream server refused the connection."),
+		SendRequestError::Connect(_) =>
+			(StatusCode::BAD_GATEWAY, "502 Bad Gateway", "connect", "The server was acting as a proxy and was unable to connect to the upstream server."),
+		_ => (StatusCode::BAD_GATEWAY, "502 Bad Gateway", "response", "The server was acting as a proxy and received an invalid response from the upstream server."),
+	};
+
+	println!("[Warn]: Proxied request to {} failed ({}): {}", upstream, kind, err);
+	if debug {
+		return ui::http_error(status, title, &[reason, " Error details: ", &err.to_string()].concat(), smaller)
+	}
+	ui::http_error(status, title, reason, smaller)
+}
+
 // Do a HTTP permanent redirect.
 fn redir(path: &str) -> HttpResponse {
 	HttpResponse::Ok()
@@ -121,6 +433,14 @@ fn redir(path: &str) -> HttpResponse {
 		.body(["<a href='", path, "'>If this redirect does not work, click here</a>"].concat())
 }
 
+// Get the Cache-Control header for static files, based on the caching_timeout (in hours).
+fn cache_control(cache_int: i64) -> String {
+	if cache_int == 0 {
+		return "no-store, must-revalidate".to_owned()
+	}
+	["max-age=", &(cache_int*3600).to_string(), ", public, stale-while-revalidate=", &(cache_int*900).to_string()].concat()
+}
+
 // Logs a HTTP request to the console.
 // Note: For security reasons, HTTP auth data is not included in logs.
 fn log_data(format_type: &str, status: u16, head: &str, req: &HttpRequest, conn: &ConnectionInfo, length: Option<u64>) {
@@ -207,17 +527,48 @@ fn hsts(body: Payload, req: HttpRequest) -> Either<HttpResponse, Box<Future<Item
 	}
 
 	log_data(&conf.log_format, 301, "WebHSTS", &req, &conn_info, None);
-	Either::A(redir(&["https://", host, port, req.path()].concat()))
+	let mut resp = redir(&["https://", host, port, req.path()].concat());
+	if !conf.header_rules.is_empty() {
+		let client_ip = req.peer_addr().map_or_else(|| "127.0.0.1".to_owned(), |a| a.ip().to_string());
+		conf.header_rules.response(resp.headers_mut(), &headers::Vars::new(req.head(), &client_ip, false));
+	}
+	Either::A(resp)
 }
 
-// HTTP(S) request handling.
+// HTTP(S) request handling. Any matching header rules are applied to the response.
 fn index(body: Payload, req: HttpRequest) -> Either<HttpResponse, Box<Future<Item=HttpResponse, Error=Error>>> {
+	let rules = rc(&CONFM).header_rules.to_owned();
+	if rules.is_empty() {
+		return handle(body, req)
+	}
+
+	let client_ip = req.peer_addr().map_or_else(|| "127.0.0.1".to_owned(), |a| a.ip().to_string());
+	let vars = headers::Vars::new(req.head(), &client_ip, req.app_config().secure());
+	match handle(body, req) {
+		Either::A(mut resp) => {
+			rules.response(resp.headers_mut(), &vars);
+			Either::A(resp)
+		},
+		// Errors are turned into their responses first, so that the rules are applied to error pages as well.
+		Either::B(fut) => Either::B(Box::new(fut.then(move |res| -> Result<HttpResponse, Error> {
+			let mut resp = res.unwrap_or_else(HttpResponse::from);
+			rules.response(resp.headers_mut(), &vars);
+			Ok(resp)
+		}))),
+	}
+}
+
+// Send a request to a redirect, proxy, CGI script or file.
+fn handle(body: Payload, req: HttpRequest) -> Either<HttpResponse, Box<Future<Item=HttpResponse, Error=Error>>> {
 	let conf = rc(&CONFM);
 
 	let rawpath = &percent_decode(req.path().as_bytes()).decode_utf8_lossy();
 	let conn_info = req.connection_info();
 
-	let (path, host, fp) = conf.handle_path(rawpath, conn_info.host(), req.headers().get(header::AUTHORIZATION).unwrap_or(&BLANKHEAD).to_str().unwrap_or(""));
+	// The peer address is used instead of the connection info, as the connection info trusts forwarding headers sent by the client.
+	let client_ip = req.peer_addr().map_or_else(|| "127.0.0.1".to_owned(), |a| a.ip().to_string());
+
+	let (path, host, fp) = conf.handle_path(rawpath, conn_info.host(), req.headers().get(header::AUTHORIZATION).unwrap_or(&BLANKHEAD).to_str().unwrap_or(""), req.headers(), &client_ip);
 
 	if host == "redir" {
 		if path == "unauth" {
@@ -231,9 +582,40 @@ fn index(body: Payload, req: HttpRequest) -> Either<HttpResponse, Box<Future<Ite
 	if host == "proxy" {
 		let mut path = path;
 		if !req.query_string().is_empty() {
-			path = path + "?" + req.query_string();
+			// The path already has a query string if the proxy's dest added one.
+			let sep = if path.contains('?') {"&"} else {"?"};
+			path = path + sep + req.query_string();
 		}
-		return Either::B(proxy_request(&path, req.method().to_owned(), req.headers(), body, conn_info.remote().unwrap_or("127.0.0.1"), &conf))
+		if let Some(proxy) = fp.and_then(|loc| conf.get_proxy(&loc)) {
+			return Either::B(proxy_request(&path, &proxy, &req, body, &client_ip, &conf))
+		}
+		return Either::A(ui::http_error(StatusCode::INTERNAL_SERVER_ERROR, "500 Internal Server Error", "An unexpected condition was encountered.", conf.smaller_default))
+	}
+
+	// Scripts in CGI folders are run instead of being served as files. Only the parts of the path within a CGI folder can be run.
+	if fp.as_ref().map_or(false, |f| conf.is_cgi(f)) {
+		let root = env::current_dir().unwrap_or_default().join(&host).to_string_lossy().into_owned();
+		let script = match cgi::find_script(&root, "", &path, "") {
+			Some(s) => s,
+			None => {
+				log_data(&conf.log_format, 404, "WebNotFound", &req, &conn_info, None);
+				return Either::A(ui::http_error(StatusCode::NOT_FOUND, "404 Not Found", &["The resource ", rawpath, " could not be found."].concat(), conf.smaller_default));
+			},
+		};
+
+		if !conf.is_cgi(&[&*host, &script.name].concat()) || !cgi::is_executable(&script.filename) {
+			log_data(&conf.log_format, 403, "WebCGI", &req, &conn_info, None);
+			return Either::A(ui::http_error(StatusCode::FORBIDDEN, "403 Forbidden", "The requested script can't be run.", conf.smaller_default));
+		}
+
+		if upload::too_large(req.headers(), conf.max_body_size) {
+			log_data(&conf.log_format, 413, "WebCGI", &req, &conn_info, None);
+			return Either::A(body_too_large(conf.smaller_default));
+		}
+
+		let params = cgi::params(req.head(), &script, &client_ip, req.app_config().secure(), None);
+		log_data(&conf.log_format, 200, "WebCGI", &req, &conn_info, None);
+		return Either::B(cgi::exec(&script, params, upload::limit(body, conf.max_body_size).0, conf.cgi_timeout, conf.smaller_default))
 	}
 
 	if req.method() != Method::GET && req.method() != Method::HEAD {
@@ -249,15 +631,11 @@ fn index(body: Payload, req: HttpRequest) -> Either<HttpResponse, Box<Future<Ite
 	let mime = get_mime(&full_path);
 	let mim = trim_suffix("; charset=utf-8", &mime);
 
-	// If the client accepts a brotli compressed response, then modify full_path to send one.
+	// Send the precompressed version of the file that the client prefers, if one is available. Compressed versions are created when they are first needed if compress_files is enabled.
 	let ce = req.headers().get(header::ACCEPT_ENCODING).unwrap_or(&BLANKHEAD).to_str().unwrap_or("");
-	if ce.contains("br") {
-		if conf.compress_files {
-			if let Ok(path) = stream::get_compressed_file(&*full_path, mim) {full_path = path}
-		} else if Path::new(&[&full_path, ".br"].concat()).exists() {
-			full_path = [&full_path, ".br"].concat()
-		}
-	}
+	let (compressed_path, encoding) = stream::negotiate_file(&full_path, mim, ce, conf.compress_files);
+	full_path = compressed_path;
+	let log_head = ["Web", stream::encoding_label(encoding)].concat();
 
 	// Open the file specified in full_path. If the file is not present, serve either a directory listing or an error.
 	let (f, finfo);
@@ -275,52 +653,102 @@ fn index(body: Payload, req: HttpRequest) -> Either<HttpResponse, Box<Future<Ite
 		return Either::A(redir(&[rawpath, "/"].concat()));
 	}
 
-	// Parse a ranges header if it is present, and then turn a File into a stream.
-	let (length, offset) = stream::calculate_ranges(&req, finfo.len());
-	let has_range = offset != 0 || length as u64 != finfo.len();
-	let body = if length > conf.max_streaming_len || has_range {
-		Body::from_message(BodyStream::new(stream::ChunkedReadFile {
+	// Validators come from the file which is actually sent, so the plain and compressed versions of a file never share an ETag.
+	let cache_int = conf.caching_timeout;
+	let (etag, modified) = stream::validators(&finfo, encoding);
+	match stream::check_conditions(&req, &etag, modified) {
+		Some(StatusCode::NOT_MODIFIED) => {
+			log_data(&conf.log_format, 304, "WebNotModified", &req, &conn_info, None);
+			return Either::A(HttpResponse::NotModified()
+				.header(header::ETAG, etag.to_string())
+				.header(header::VARY, "Accept-Encoding")
+				.if_some(modified, |m, builder| {
+					builder.header(header::LAST_MODIFIED, stream::http_date(m));
+				})
+				.header(header::CACHE_CONTROL, cache_control(cache_int))
+				.header(header::SERVER, "KatWebX")
+				.finish())
+		},
+		Some(status) => {
+			log_data(&conf.log_format, status.as_u16(), "WebPrecondition", &req, &conn_info, None);
+			return Either::A(ui::http_error(status, "412 Precondition Failed", &["The resource ", rawpath, " doesn't match the request's conditions."].concat(), conf.smaller_default))
+		},
+		None => (),
+	}
+
+	// Parse a ranges header if it is present, and then turn a File into a stream. Requests for several ranges get a multipart response, with each range sent as a separate part.
+	let total = finfo.len();
+	let ranges = match stream::calculate_ranges(&req, total, &etag, modified) {
+		Ranges::Full => Vec::new(),
+		Ranges::Partial(r) => r,
+		Ranges::Unsatisfiable => {
+			log_data(&conf.log_format, 416, "WebBadRange", &req, &conn_info, None);
+			let mut resp = ui::http_error(StatusCode::RANGE_NOT_SATISFIABLE, "416 Range Not Satisfiable", &["The requested range of ", rawpath, " is outside of the resource."].concat(), conf.smaller_default);
+			if let Ok(v) = HeaderValue::from_str(&["bytes */", &total.to_string()].concat()) {
+				resp.headers_mut().insert(header::CONTENT_RANGE, v);
+			}
+			return Either::A(resp)
+		},
+	};
+	let has_range = !ranges.is_empty();
+	let boundary = if ranges.len() > 1 {Some(stream::boundary())} else {None};
+	let (length, offset) = ranges.first().map_or((total, 0), |r| (r.length, r.start));
+
+	let (length, body) = if let Some(boundary) = boundary.as_ref() {
+		match stream::multipart_ranges(&f, &ranges, total, &mime, boundary, conf.max_streaming_len) {
+			Ok((len, parts)) => (len, Body::from_message(BodyStream::new(parts))),
+			Err(_) => {
+				log_data(&conf.log_format, 500, "WebError", &req, &conn_info, None);
+				return Either::A(ui::http_error(StatusCode::INTERNAL_SERVER_ERROR, "500 Internal Server Error", "An unexpected condition was encountered.", conf.smaller_default))
+			},
+		}
+	} else if length > conf.max_streaming_len || has_range {
+		(length, Body::from_message(BodyStream::new(stream::ChunkedReadFile {
 			offset,
 			size: length,
 			file: Some(f),
 			fut: None,
 			counter: 0,
 			chunk_size: conf.max_streaming_len,
-		}))
+		})))
 	} else if length == 0 {
-		Body::Bytes(Bytes::from("\n"))
+		(length, Body::Bytes(Bytes::from("\n")))
 	} else {
-		Body::Bytes(stream::read_file(f).unwrap_or_else(|_| Bytes::from("")))
+		(length, Body::Bytes(stream::read_file(f).unwrap_or_else(|_| Bytes::from(""))))
 	};
 
-	log_data(&conf.log_format, 200, "Web", &req, &conn_info, Some(length));
+	log_data(&conf.log_format, if has_range {206} else {200}, &log_head, &req, &conn_info, Some(length));
 
 	// Craft a response.
-	let cache_int = conf.caching_timeout;
 	Either::A(HttpResponse::Ok()
-			.if_true(&*mime != "unknown/unknown", |builder| { // Only specify a MIME type if we know one. If we do know one, don't let the browser override our decision.
+			.if_true(&*mime != "unknown/unknown" && boundary.is_none(), |builder| { // Only specify a MIME type if we know one. If we do know one, don't let the browser override our decision.
 				builder.content_type(&*mime);
 				builder.header(header::X_CONTENT_TYPE_OPTIONS, "nosniff");
 			})
 			.header(header::ACCEPT_RANGES, "bytes")
 			.header(header::CONTENT_LENGTH, length.to_string())
-			.if_true(full_path.ends_with(".br"), |builder| {
-				builder.header(header::CONTENT_ENCODING, "br");
+			.header(header::ETAG, etag.to_string())
+			.if_some(modified, |m, builder| {
+				builder.header(header::LAST_MODIFIED, stream::http_date(m));
+			})
+			.header(header::VARY, "Accept-Encoding")
+			.if_true(!encoding.is_empty(), |builder| {
+				builder.header(header::CONTENT_ENCODING, encoding);
 				builder.encoding(ContentEncoding::Identity);
 			})
-			.if_true(!full_path.ends_with(".br") && stream::GZTYPES.binary_search(&&*mim).is_err(), |builder| {
+			.if_true(encoding.is_empty() && stream::GZTYPES.binary_search(&&*mim).is_err(), |builder| {
 				builder.encoding(ContentEncoding::Identity);
 			})
 			.if_true(has_range, |builder| {
 				builder.status(StatusCode::PARTIAL_CONTENT);
-				builder.header(header::CONTENT_RANGE, ["bytes ", &offset.to_string(), "-", &(offset+length-1).to_string(), "/", &finfo.len().to_string()].concat());
 			})
-			.if_true(cache_int == 0, |builder| {
-				builder.header(header::CACHE_CONTROL, "no-store, must-revalidate");
+			.if_some(boundary.as_ref().map(String::as_str), |boundary, builder| {
+				builder.content_type(["multipart/byteranges; boundary=", boundary].concat());
 			})
-			.if_true(cache_int != 0, |builder| {
-				builder.header(header::CACHE_CONTROL, ["max-age=", &(cache_int*3600).to_string(), ", public, stale-while-revalidate=", &(cache_int*900).to_string()].concat());
+			.if_true(ranges.len() == 1, |builder| {
+				builder.header(header::CONTENT_RANGE, ["bytes ", &offset.to_string(), "-", &(offset+length-1).to_string(), "/", &total.to_string()].concat());
 			})
+			.header(header::CACHE_CONTROL, cache_control(cache_int))
 			.if_true(conf.hsts, |builder| {
 				builder.header(header::STRICT_TRANSPORT_SECURITY, "max-age=31536000;includeSubDomains;preload");
 			})
@@ -333,6 +761,14 @@ fn index(body: Payload, req: HttpRequest) -> Either<HttpResponse, Box<Future<Ite
             .body(body))
 }
 
+// Start forwarding [[stream]] ports. These are only started once, as the listeners can't be changed by reloading the config.
+fn start_streams(conf: &Config) {
+	tcp::stream_server(&conf.streams).unwrap_or_else(|err| {
+		println!("[Fatal]: Unable to bind to a stream's listen address! ({})", err);
+		process::exit(exitcode::NOPERM);
+	});
+}
+
 // Load configuration, SSL certs, then attempt to start the program.
 fn main() {
 	println!("[Warn]: You are using an unstable Git version of KatWebX. You WILL experience bugs, documentation will likely not be 100% accurate, and some functionality may not work properly. Never use Git versions in production, unless you know the code well, and are prepared to deal with issues as they come up.");
@@ -341,6 +777,7 @@ fn main() {
 	lazy_static::initialize(&CONFM);
 	lazy_static::initialize(&BLANKHEAD);
 	lazy_static::initialize(&stream::GZTYPES);
+	rc(&CONFM).start_health_checks();
 	let conf = Config::load_config(std::env::args().nth(1).unwrap_or_else(|| "conf.toml".to_owned()), true); // We can't hold the RwLock on the main thread, or we won't be able to reload the config. We'll have to read the config manually.
 	env::set_current_dir(conf.root_folder.to_owned()).unwrap_or_else(|_| {
 		println!("[Fatal]: Unable to open root folder!");
@@ -401,7 +838,9 @@ fn main() {
 					println!("Hot-reloading the config safely isn't perfect. You should never encounter this error, but if you do, please report it on KatWebX's GitHub.");
 					process::exit(exitcode::SOFTWARE);
 				});
+				conf.inherit_health(&confw);
 				*confw = conf;
+				confw.start_health_checks();
 				println!("[Info]: Reload sucessful!");
 			}
 		});
@@ -409,25 +848,18 @@ fn main() {
 		// Socket request handling
 		let mut listenfd = ListenFd::from_env();
 		if let Ok(Some(l)) = listenfd.take_tcp_listener(0) {
-			HttpServer::new(
-				|| App::new().route("/*", web::to(hsts)))
-			.keep_alive(conf.stream_timeout as usize)
-			.listen(l).unwrap_or_else(|_err| {
+			tcp::http_server(l, conf.keep_alive, conf.http_proxy_protocol).unwrap_or_else(|_err| {
 				println!("[Fatal]: Unable to initialize socket!");
 				process::exit(exitcode::DATAERR);
-			})
-			.start();
+			});
 
 			if let Ok(Some(li)) = listenfd.take_tcp_listener(1) {
-				HttpServer::new(
-					|| App::new().route("/*", web::to(index)))
-				.keep_alive(conf.stream_timeout as usize)
-				.listen_rustls(li, tconfig).unwrap_or_else(|_err| {
+				tcp::tls_server(li, tconfig, conf.keep_alive, conf.tls_proxy_protocol).unwrap_or_else(|_err| {
 					println!("[Fatal]: Unable to initialize socket!");
 					process::exit(exitcode::DATAERR);
-				})
-		        .start();
+				});
 			}
+			start_streams(&conf);
 
 			println!("[Info]: Started KatWebX in socket mode.");
 			let _ = sys.run();
@@ -436,26 +868,20 @@ fn main() {
 		}
 	}
 
-	// TCP request handling
-	HttpServer::new(
-		|| App::new().route("/*", web::to(index)))
-		.keep_alive(conf.stream_timeout as usize)
-		.bind_rustls(&conf.tls_addr, tconfig)
+	/* TCP request handling. The listeners are built in tcp.rs, as they read the start of each connection for TLS passthrough and the PROXY protocol.
+This also lets uploads which are too large be rejected before the client sends the body. */
+	net::TcpListener::bind(&conf.tls_addr).and_then(|l| tcp::tls_server(l, tconfig, conf.keep_alive, conf.tls_proxy_protocol))
 		.unwrap_or_else(|_err| {
 			println!("{}", ["[Fatal]: Unable to bind to ", &conf.tls_addr, "!"].concat());
 			process::exit(exitcode::NOPERM);
-		})
-        .start();
+		});
+	start_streams(&conf);
 
-	HttpServer::new(
-		|| App::new().route("/*", web::to(hsts)))
-		.keep_alive(conf.stream_timeout as usize)
-		.bind(&conf.http_addr)
+	net::TcpListener::bind(&conf.http_addr).and_then(|l| tcp::http_server(l, conf.keep_alive, conf.http_proxy_protocol))
 		.unwrap_or_else(|_err| {
 			println!("{}", ["[Fatal]: Unable to bind to ", &conf.http_addr, "!"].concat());
 			process::exit(exitcode::NOPERM);
-		})
-	    .start();
+		});
 
 	println!("[Info]: Started KatWebX.");
 	let _ = sys.run();
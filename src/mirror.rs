@@ -0,0 +1,122 @@
+// Mirror.rs handles copying proxied requests to a secondary upstream server, without affecting the original request.
+extern crate actix;
+extern crate actix_web;
+extern crate futures;
+extern crate bytes;
+
+use futures::{Async, Future, Poll, Stream, sync::mpsc};
+use bytes::Bytes;
+use std::{sync::{Arc, atomic::{AtomicUsize, Ordering}}, time::Instant};
+use self::actix::Arbiter;
+use actix_web::{client::Client, error::{self, PayloadError}, http::{header::HeaderMap, Method}};
+use upload::Upload;
+
+// The largest amount of the request body that can be waiting to be sent to the mirror, in bytes. If the mirror falls further behind than this, the mirrored request is cancelled instead of slowing down the original request.
+const MAX_PENDING: usize = 4_194_304;
+
+// A mirror upstream, which receives a copy of a percentage of the requests sent to a proxy location.
+pub struct Mirror {
+	pub dest: String,
+	percent: usize,
+	counter: AtomicUsize,
+}
+
+impl Mirror {
+	pub fn new(dest: String, percent: usize) -> Self {
+		Self { dest, percent: if percent > 100 {100} else {percent}, counter: AtomicUsize::new(0) }
+	}
+
+	// Check if the next request should be mirrored. Requests are picked evenly, so that exactly the configured percentage of requests are mirrored.
+	pub fn sample(&self) -> bool {
+		let n = self.counter.fetch_add(1, Ordering::Relaxed) % 100;
+		n * self.percent / 100 != (n + 1) * self.percent / 100
+	}
+}
+
+/* Send a copy of a request to the mirror in the background. The returned stream passes the request body through to the original request, while copying it to the mirror.
+Once the mirror responds, its status and latency are logged, unless logging is disabled. The minimal log format only logs failed mirror requests. */
+pub fn send(client: &Client, url: &str, method: Method, headers: &HeaderMap, body: Upload, log_format: &str, log: String) -> Tee {
+	let (tx, rx) = mpsc::unbounded();
+	let pending = Arc::new(AtomicUsize::new(0));
+	let rx_pending = pending.to_owned();
+	let rx = rx.then(move |res: Result<Result<Bytes, ()>, ()>| {
+		res.unwrap_or(Err(())).map(|chunk| {
+			rx_pending.fetch_sub(chunk.len(), Ordering::SeqCst);
+			chunk
+		})
+	}).map_err(|_| error::ErrorBadGateway("The mirrored request body was cancelled"));
+
+	let mut req = client.request(method, url);
+	for (key, value) in headers.iter() {
+		req = req.header(key.to_owned(), value.to_owned());
+	}
+
+	let (quiet, minimal) = (log_format == "" || log_format == "none", log_format == "minimal");
+	let start = Instant::now();
+	Arbiter::spawn(req.send_stream(rx).then(move |res| {
+		let ms = start.elapsed().as_millis();
+		match res {
+			Ok(ref resp) if quiet || (minimal && resp.status().as_u16() < 399) => (),
+			Ok(resp) => println!("[ProxyMirror {} {}ms]{}", resp.status().as_u16(), ms, log),
+			Err(_) if quiet => (),
+			Err(err) => println!("[ProxyMirror Failed {}ms]{} ({})", ms, log, err),
+		}
+		Ok(())
+	}));
+
+	Tee { body, tx: Some(tx), pending }
+}
+
+// Tee passes a request body through, while copying it to a mirror if there is one.
+pub struct Tee {
+	body: Upload,
+	tx: Option<mpsc::UnboundedSender<Result<Bytes, ()>>>,
+	pending: Arc<AtomicUsize>,
+}
+
+impl Tee {
+	// Pass a request body through without mirroring it.
+	pub fn new(body: Upload) -> Self {
+		Self { body, tx: None, pending: Arc::new(AtomicUsize::new(0)) }
+	}
+
+	// Cancel the mirrored request, by sending it an error instead of the rest of the body.
+	fn cancel(&mut self) {
+		if let Some(tx) = self.tx.take() {
+			let _ = tx.unbounded_send(Err(()));
+		}
+	}
+}
+
+impl Stream for Tee {
+	type Item = Bytes;
+	type Error = PayloadError;
+
+	fn poll(&mut self) -> Poll<Option<Bytes>, PayloadError> {
+		let res = self.body.poll();
+		if self.tx.is_none() {
+			return res
+		}
+
+		match res {
+			Ok(Async::Ready(Some(ref chunk))) => {
+				if self.pending.fetch_add(chunk.len(), Ordering::SeqCst) + chunk.len() > MAX_PENDING {
+					self.cancel()
+				} else if let Some(tx) = self.tx.as_ref() {
+					let _ = tx.unbounded_send(Ok(chunk.to_owned()));
+				}
+			},
+			Ok(Async::Ready(None)) => self.tx = None,
+			Ok(Async::NotReady) => (),
+			Err(_) => self.cancel(),
+		}
+		res
+	}
+}
+
+// If the request body is dropped before it has been fully read, the mirrored request is cancelled, so that the mirror doesn't receive a truncated body as if it were complete.
+impl Drop for Tee {
+	fn drop(&mut self) {
+		self.cancel()
+	}
+}
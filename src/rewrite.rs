@@ -0,0 +1,150 @@
+// Rewrite.rs handles rewriting URLs and cookies in responses from proxied servers, so that they point to the proxy instead of the upstream server.
+extern crate actix_web;
+use actix_web::http::header::{HeaderMap, HeaderValue};
+
+pub struct Rewriter {
+	urls: Vec<(String, String)>,
+	prefix: String,
+	cookie_domains: Vec<(String, String)>,
+	cookie_paths: Vec<(String, String)>,
+}
+
+impl Rewriter {
+	/* Create a rewriter for a single request. URLs starting with the "from" half of a rule are changed to start with the "to" half instead.
+	If auto is set, URLs pointing to any of the upstreams are changed to point to base (the public URL of the proxy location), and absolute paths have the location's path prefix added. */
+	pub fn new(rules: &[(String, String)], auto: &[&str], base: &str, prefix: &str, cookie_domains: &[(String, String)], cookie_paths: &[(String, String)]) -> Self {
+		let mut urls = rules.to_vec();
+		for dest in auto {
+			urls.push((dest.trim_end_matches('/').to_owned(), base.trim_end_matches('/').to_owned()));
+		}
+
+		Self {
+			urls,
+			prefix: if auto.is_empty() {String::new()} else {prefix.trim_end_matches('/').to_owned()},
+			cookie_domains: cookie_domains.to_vec(),
+			cookie_paths: cookie_paths.to_vec(),
+		}
+	}
+
+	// Rewrite the headers of a response. Headers which don't need to be rewritten are copied as-is.
+	pub fn apply(&self, headers: &HeaderMap) -> HeaderMap {
+		let mut out = HeaderMap::new();
+		for (key, value) in headers.iter() {
+			let new = match (key.as_str(), value.to_str()) {
+				("location", Ok(v)) | ("content-location", Ok(v)) => self.url(v),
+				("refresh", Ok(v)) => self.refresh(v),
+				("set-cookie", Ok(v)) => self.cookie(v),
+				_ => None,
+			};
+
+			out.append(key.to_owned(), new.and_then(|n| HeaderValue::from_str(&n).ok()).unwrap_or_else(|| value.to_owned()));
+		}
+		out
+	}
+
+	// URLs only match a rule if the rule ends at a path, query or fragment boundary, so that a rule for port 8081 doesn't match port 80812.
+	fn url(&self, value: &str) -> Option<String> {
+		for (from, to) in &self.urls {
+			if !value.starts_with(from.as_str()) {
+				continue
+			}
+			let rest = &value[from.len()..];
+			if from.ends_with('/') || rest.is_empty() || rest.starts_with(|c: char| c == '/' || c == '?' || c == '#') {
+				return Some([to, rest].concat())
+			}
+		}
+
+		// Relative redirects are relative to the upstream's root, which is the location's prefix for the client.
+		if !self.prefix.is_empty() && value.starts_with('/') && !value.starts_with("//") {
+			return Some([&self.prefix, value].concat())
+		}
+		None
+	}
+
+	// The Refresh header is formatted as "5; url=https://example.com", only the URL needs to be changed.
+	fn refresh(&self, value: &str) -> Option<String> {
+		let i = value.to_lowercase().find("url=")? + 4;
+		let url = value[i..].trim_matches(|c| c == '\'' || c == '"');
+		Some([&value[..i], &self.url(url)?].concat())
+	}
+
+	// Change the Domain and Path attributes of a cookie, leaving everything else alone.
+	fn cookie(&self, value: &str) -> Option<String> {
+		let mut changed = false;
+		let attrs: Vec<String> = value.split(';').enumerate().map(|(i, attr)| {
+			// The first part of a cookie is its name and value, which is never changed.
+			if i == 0 {
+				return attr.trim().to_owned()
+			}
+
+			let mut parts = attr.trim().splitn(2, '=');
+			let name = parts.next().unwrap_or("").to_lowercase();
+			let val = parts.next().unwrap_or("");
+
+			let new = match name.as_str() {
+				"domain" => self.cookie_domains.iter().find(|(from, _)| from.trim_start_matches('.').eq_ignore_ascii_case(val.trim_start_matches('.'))).map(|(_, to)| to.to_owned()),
+				"path" => self.cookie_paths.iter().find(|(from, _)| val.starts_with(from.as_str())).map(|(from, to)| [to, &val[from.len()..]].concat()),
+				_ => None,
+			};
+
+			match new {
+				Some(n) => {
+					changed = true;
+					[attr.trim().splitn(2, '=').next().unwrap_or(""), "=", &n].concat()
+				},
+				None => attr.trim().to_owned(),
+			}
+		}).collect();
+
+		if changed {
+			return Some(attrs.join("; "))
+		}
+		None
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Rewriter;
+	use actix_web::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+
+	fn rewrite(rewriter: &Rewriter, name: HeaderName, value: &'static str) -> String {
+		let mut headers = HeaderMap::new();
+		headers.insert(name.to_owned(), HeaderValue::from_static(value));
+		rewriter.apply(&headers).get(name).and_then(|v| v.to_str().ok()).unwrap_or("").to_owned()
+	}
+
+	fn rewriter() -> Rewriter {
+		let rules = [("https://old.example.com/".to_owned(), "https://example.com/new/".to_owned())];
+		let domains = [("internal.local".to_owned(), "example.com".to_owned())];
+		let paths = [("/".to_owned(), "/app/".to_owned())];
+		Rewriter::new(&rules, &["http://127.0.0.1:8080/"], "https://example.com/app/", "/app/", &domains, &paths)
+	}
+
+	#[test]
+	fn urls() {
+		let r = rewriter();
+		assert_eq!(rewrite(&r, header::LOCATION, "http://127.0.0.1:8080/login?a=b"), "https://example.com/app/login?a=b");
+		assert_eq!(rewrite(&r, header::LOCATION, "https://old.example.com/page"), "https://example.com/new/page");
+		assert_eq!(rewrite(&r, header::LOCATION, "/login"), "/app/login");
+		assert_eq!(rewrite(&r, header::LOCATION, "//cdn.example.com/a"), "//cdn.example.com/a");
+		assert_eq!(rewrite(&r, header::LOCATION, "https://other.example.com/"), "https://other.example.com/");
+		assert_eq!(rewrite(&r, header::LOCATION, "http://127.0.0.1:8080"), "https://example.com/app");
+		assert_eq!(rewrite(&r, header::LOCATION, "http://127.0.0.1:8080?a=b"), "https://example.com/app?a=b");
+		assert_eq!(rewrite(&r, header::LOCATION, "http://127.0.0.1:80801/x"), "http://127.0.0.1:80801/x");
+		assert_eq!(rewrite(&r, header::REFRESH, "5; url=http://127.0.0.1:8080/done"), "5; url=https://example.com/app/done");
+	}
+
+	#[test]
+	fn cookies() {
+		let r = rewriter();
+		assert_eq!(rewrite(&r, header::SET_COOKIE, "id=1; Domain=.internal.local; Path=/account; HttpOnly"), "id=1; Domain=example.com; Path=/app/account; HttpOnly");
+		assert_eq!(rewrite(&r, header::SET_COOKIE, "id=1; Domain=other.example.com; Secure"), "id=1; Domain=other.example.com; Secure");
+	}
+
+	#[test]
+	fn without_auto() {
+		let r = Rewriter::new(&[], &[], "https://example.com/app/", "/app/", &[], &[]);
+		assert_eq!(rewrite(&r, header::LOCATION, "/login"), "/login");
+	}
+}
@@ -10,14 +10,17 @@ extern crate actix_web;
 extern crate futures;
 extern crate futures_cpupool;
 extern crate brotli;
+extern crate flate2;
+extern crate zstd;
 extern crate bytes;
 
-use futures::{Async, Future, Poll, Stream};
+use futures::{Async, Future, Poll, Stream, stream};
 use bytes::Bytes;
-use std::{io, io::{Error, Seek, Read}, fs::{File, Metadata}, cmp, path::Path};
-use actix_web::{web, HttpRequest, http::header};
+use std::{io, io::{Error, Seek, Read}, fs::{self, File, Metadata}, cmp, path::Path, sync::atomic::{AtomicUsize, Ordering}, time::{Duration, SystemTime, UNIX_EPOCH}};
+use actix_web::{web, HttpRequest, http::{header, header::{EntityTag, Header, HttpDate, IfMatch, IfModifiedSince, IfNoneMatch, IfRange, IfUnmodifiedSince}, StatusCode}};
 use actix_web::error::{BlockingError, ErrorInternalServerError};
 use self::brotli::{BrotliCompress, enc::encode::BrotliEncoderInitParams};
+use self::flate2::{Compression, write::GzEncoder};
 
 lazy_static! {
 	/* A non-exaustive list of MIME types that should compress well. Note that this list MUST be in alphabetical order, with no duplicate items.
@@ -78,38 +81,254 @@ pub fn open_meta(path: &str) -> Result<(File, Metadata), Error> {
 	Ok((f, m))
 }
 
-pub fn get_compressed_file(path: &str, mime: &str) -> Result<String, Error> {
-	if Path::new(&[path, ".br"].concat()).exists() {
-		return Ok([path, ".br"].concat())
+/* Generate validators for a file from its metadata, which are its ETag and its modification time (in seconds since the Unix epoch).
+Compressed versions of a file are a different representation of it, so the encoding is included in their ETag. */
+pub fn validators(meta: &Metadata, encoding: &str) -> (EntityTag, Option<u64>) {
+	let modified = meta.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok());
+	let mtime = modified.map_or_else(String::new, |t| format!("-{:x}.{:x}", t.as_secs(), t.subsec_nanos()));
+	let suffix = if encoding.is_empty() {String::new()} else {["-", encoding].concat()};
+	(EntityTag::strong([&format!("{:x}", meta.len()), &mtime, &suffix].concat()), modified.map(|t| t.as_secs()))
+}
+
+// Convert a modification time from validators into a date for the Last-Modified header.
+pub fn http_date(secs: u64) -> HttpDate {
+	HttpDate::from(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/* Check the conditional headers of a GET or HEAD request against a file's validators, following the order in RFC 7232.
+Some(304) is returned if the client's copy is still current, and Some(412) is returned if the file doesn't match what the client expected. Dates are compared in whole seconds, as HTTP dates can't be more precise. */
+pub fn check_conditions(req: &HttpRequest, etag: &EntityTag, modified: Option<u64>) -> Option<StatusCode> {
+	let headers = req.headers();
+	let changed_since = |date: HttpDate| match (modified, SystemTime::from(date).duration_since(UNIX_EPOCH)) {
+		(Some(m), Ok(d)) => Some(m > d.as_secs()),
+		_ => None,
+	};
+
+	if headers.contains_key(header::IF_MATCH) {
+		let matched = match IfMatch::parse(req) {
+			Ok(IfMatch::Any) => true,
+			Ok(IfMatch::Items(tags)) => tags.iter().any(|t| t.strong_eq(etag)),
+			Err(_) => false,
+		};
+		if !matched {
+			return Some(StatusCode::PRECONDITION_FAILED)
+		}
+	} else if let Ok(IfUnmodifiedSince(date)) = IfUnmodifiedSince::parse(req) {
+		if changed_since(date) == Some(true) {
+			return Some(StatusCode::PRECONDITION_FAILED)
+		}
+	}
+
+	if headers.contains_key(header::IF_NONE_MATCH) {
+		let matched = match IfNoneMatch::parse(req) {
+			Ok(IfNoneMatch::Any) => true,
+			Ok(IfNoneMatch::Items(tags)) => tags.iter().any(|t| t.weak_eq(etag)),
+			Err(_) => false,
+		};
+		if matched {
+			return Some(StatusCode::NOT_MODIFIED)
+		}
+	} else if let Ok(IfModifiedSince(date)) = IfModifiedSince::parse(req) {
+		if changed_since(date) == Some(false) {
+			return Some(StatusCode::NOT_MODIFIED)
+		}
+	}
+	None
+}
+
+// The encodings that files can be precompressed with, along with the extension of the compressed file and the name used in logs. When the client has no preference, they are used in this order.
+const ENCODINGS: [(&str, &str, &str); 3] = [("br", ".br", "Br"), ("zstd", ".zst", "Zstd"), ("gzip", ".gz", "Gzip")];
+
+/* Get the encodings a client accepts from its Accept-Encoding header, in the order they should be used. Encodings with a q-value of 0 are left out.
+"identity" is included where the client prefers an uncompressed response, and is last if the client doesn't mention it. */
+pub fn accepted_encodings(accept: &str) -> Vec<&'static str> {
+	let mut listed = Vec::new();
+	let mut wildcard = None;
+	for item in accept.split(',') {
+		let mut params = item.split(';').map(str::trim);
+		let name = params.next().unwrap_or("").to_lowercase();
+		let q = params.filter_map(|p| if p.starts_with("q=") || p.starts_with("Q=") {p[2..].parse::<f32>().ok()} else {None}).next().unwrap_or(1.0);
+		match name.as_str() {
+			"" => (),
+			"*" => wildcard = Some(q),
+			_ => listed.push((name, q)),
+		}
+	}
+
+	let names = ENCODINGS.iter().map(|e| e.0).chain(Some("identity"));
+	let mut accepted: Vec<(&'static str, f32)> = names.filter_map(|name| {
+		let q = match listed.iter().find(|(n, _)| n == name) {
+			Some((_, q)) => *q,
+			None if name == "identity" => wildcard.unwrap_or(0.001).max(0.001),
+			None => wildcard.unwrap_or(0.0),
+		};
+		if q > 0.0 {Some((name, q))} else {None}
+	}).collect();
+
+	// The sort is stable, so encodings with the same q-value stay in the server's order.
+	accepted.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(cmp::Ordering::Equal));
+	accepted.into_iter().map(|(name, _)| name).collect()
+}
+
+/* Pick the best version of a file for a client's Accept-Encoding header. If create is enabled, compressed versions of files which compress well are created when they are first needed.
+The path to send is returned, along with its encoding, which is empty if the file is sent uncompressed. */
+pub fn negotiate_file(path: &str, mime: &str, accept: &str, create: bool) -> (String, &'static str) {
+	for encoding in accepted_encodings(accept) {
+		let ext = match ENCODINGS.iter().find(|e| e.0 == encoding) {
+			Some(e) => e.1,
+			None => break,
+		};
+
+		if Path::new(&[path, ext].concat()).exists() {
+			return ([path, ext].concat(), encoding)
+		}
+		if create {
+			match get_compressed_file(path, mime, encoding) {
+				Ok(ref p) if p == path => break,
+				Ok(p) => return (p, encoding),
+				Err(_) => continue,
+			}
+		}
+	}
+	(path.to_owned(), "")
+}
+
+// Get the name used in logs for an encoding picked by negotiate_file.
+pub fn encoding_label(encoding: &str) -> &'static str {
+	ENCODINGS.iter().find(|e| e.0 == encoding).map_or("", |e| e.2)
+}
+
+// Used to give each compressed file being written a unique temporary name.
+static TEMP_ID: AtomicUsize = AtomicUsize::new(0);
+
+// Get a compressed version of a file, creating it if the file's type compresses well. The file's own path is returned if it can't be compressed.
+pub fn get_compressed_file(path: &str, mime: &str, encoding: &str) -> Result<String, Error> {
+	let ext = ENCODINGS.iter().find(|e| e.0 == encoding).map_or(".br", |e| e.1);
+	let compressed = [path, ext].concat();
+	if Path::new(&compressed).exists() {
+		return Ok(compressed)
 	}
 
-	if Path::new(&path).exists() && !Path::new(&[path, ".br"].concat()).exists() && GZTYPES.binary_search(&&*mime).is_ok() {
-		let mut fileold = File::open(path)?;
-		let mut filenew = File::create(&[path, ".br"].concat())?;
-		let _ = BrotliCompress(&mut fileold, &mut filenew, &BrotliEncoderInitParams())?;
-		return Ok([path, ".br"].concat())
+	if Path::new(&path).exists() && GZTYPES.binary_search(&&*mime).is_ok() {
+		// The file is compressed into a temporary file first, so that a partly written file is never served, even if another request compresses it at the same time.
+		let temp = [&compressed, ".", &TEMP_ID.fetch_add(1, Ordering::Relaxed).to_string(), ".tmp"].concat();
+		let res = File::open(path).and_then(|mut fileold| {
+			let mut filenew = File::create(&temp)?;
+			match encoding {
+				"zstd" => zstd::stream::copy_encode(&mut fileold, &mut filenew, 19)?,
+				"gzip" => {
+					let mut encoder = GzEncoder::new(filenew, Compression::best());
+					io::copy(&mut fileold, &mut encoder)?;
+					encoder.finish()?;
+				},
+				_ => {let _ = BrotliCompress(&mut fileold, &mut filenew, &BrotliEncoderInitParams())?;},
+			}
+			fs::rename(&temp, &compressed)
+		});
+		if let Err(err) = res {
+			let _ = fs::remove_file(&temp);
+			return Err(err)
+		}
+		return Ok(compressed)
 	}
 
 	Ok(path.to_string())
 }
 
-// The below code is copied from actix-files, with minor modifications. Actix Copyright (c) 2017 Nikolay Kim
+// The most ranges that are served for one request. Requests for more ranges get the whole file, so that clients can't make a response much larger than the file.
+const MAX_RANGES: usize = 32;
 
-pub fn calculate_ranges(req: &HttpRequest, length: u64) -> (u64, u64) {
-	if let Some(ranges) = req.headers().get(header::RANGE) {
-		if let Ok(rangesheader) = ranges.to_str() {
-			if let Ok(rangesvec) = HttpRange::parse(rangesheader, length) {
-				return (rangesvec[0].length, rangesvec[0].start)
-			} else {
-				return (length, 0);
-			};
-		} else {
-			return (length, 0);
-		};
+// Used to give each multipart response a unique boundary.
+static BOUNDARIES: AtomicUsize = AtomicUsize::new(0);
+
+// The parts of a file which were requested by a Range header.
+pub enum Ranges {
+	Full,
+	Partial(Vec<HttpRange>),
+	Unsatisfiable,
+}
+
+/* Parse a request's Range header against a file's length and validators. Invalid Range headers are ignored, while ranges which are all outside of the file are unsatisfiable.
+If-Range makes ranges only apply if the file hasn't changed, so that resumed downloads of a changed file get the whole new file instead. */
+pub fn calculate_ranges(req: &HttpRequest, length: u64, etag: &EntityTag, modified: Option<u64>) -> Ranges {
+	let rangesheader = match req.headers().get(header::RANGE).and_then(|h| h.to_str().ok()) {
+		Some(h) => h,
+		None => return Ranges::Full,
 	};
-	(length, 0)
+
+	if req.headers().contains_key(header::IF_RANGE) {
+		let unchanged = match IfRange::parse(req) {
+			Ok(IfRange::EntityTag(tag)) => tag.strong_eq(etag),
+			Ok(IfRange::Date(date)) => modified.is_some() && SystemTime::from(date).duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs()) == modified,
+			Err(_) => false,
+		};
+		if !unchanged {
+			return Ranges::Full
+		}
+	}
+
+	// Ranges which add up to more than the file are most likely abusive, so the whole file is sent instead.
+	match HttpRange::parse(rangesheader, length) {
+		Ok(ref ranges) if ranges.len() > MAX_RANGES || ranges.iter().map(|r| r.length).sum::<u64>() > length => Ranges::Full,
+		Ok(ranges) => {
+			let ranges = coalesce_ranges(ranges.into_iter().filter(|r| r.length > 0).collect());
+			if ranges.is_empty() {Ranges::Unsatisfiable} else {Ranges::Partial(ranges)}
+		},
+		Err(_) => Ranges::Full,
+	}
+}
+
+// Merge ranges which overlap or are next to each other, so that no part of the file is sent more than once. The merged ranges are sorted by their position in the file.
+fn coalesce_ranges(mut ranges: Vec<HttpRange>) -> Vec<HttpRange> {
+	ranges.sort_by_key(|r| r.start);
+	let mut merged: Vec<HttpRange> = Vec::with_capacity(ranges.len());
+	for range in ranges {
+		if let Some(last) = merged.last_mut() {
+			if range.start <= last.start + last.length {
+				last.length = last.length.max(range.start + range.length - last.start);
+				continue
+			}
+		}
+		merged.push(range);
+	}
+	merged
 }
 
+// Generate a boundary for a multipart response, which won't appear in the file's contents in practice.
+pub fn boundary() -> String {
+	let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
+	format!("katwebx-{:x}-{:x}", nanos, BOUNDARIES.fetch_add(1, Ordering::Relaxed))
+}
+
+/* Build a multipart/byteranges body, which sends each range of a file as a separate part. The ranges are streamed from the file in chunks, like single ranges are.
+The length of the whole body is returned along with it. */
+pub fn multipart_ranges(f: &File, ranges: &[HttpRange], total: u64, mime: &str, boundary: &str, chunk_size: u64) -> Result<(u64, Box<Stream<Item=Bytes, Error=actix_web::Error>>), Error> {
+	let mut parts: Vec<Box<Stream<Item=Bytes, Error=actix_web::Error>>> = Vec::new();
+	let mut length = 0;
+
+	for range in ranges {
+		let content_type = if mime == "unknown/unknown" {String::new()} else {["Content-Type: ", mime, "\r\n"].concat()};
+		let head = ["\r\n--", boundary, "\r\n", &content_type, "Content-Range: bytes ", &range.start.to_string(), "-", &(range.start+range.length-1).to_string(), "/", &total.to_string(), "\r\n\r\n"].concat();
+		length += head.len() as u64 + range.length;
+		parts.push(Box::new(stream::once(Ok(Bytes::from(head)))));
+		parts.push(Box::new(ChunkedReadFile {
+			offset: range.start,
+			size: range.length,
+			file: Some(f.try_clone()?),
+			fut: None,
+			counter: 0,
+			chunk_size,
+		}));
+	}
+
+	let end = ["\r\n--", boundary, "--\r\n"].concat();
+	length += end.len() as u64;
+	parts.push(Box::new(stream::once(Ok(Bytes::from(end)))));
+	Ok((length, Box::new(stream::iter_ok::<_, actix_web::Error>(parts).flatten())))
+}
+
+// The below code is copied from actix-files, with minor modifications. Actix Copyright (c) 2017 Nikolay Kim
+
 pub struct HttpRange {
     pub start: u64,
     pub length: u64,
@@ -121,14 +340,13 @@ const PREFIX_LEN: usize = 6;
 impl HttpRange {
     pub fn parse(header: &str, size: u64) -> Result<Vec<Self>, ()> {
         if header.is_empty() {
-            return Ok(Vec::new());
+            return Err(());
         }
         if !header.starts_with(PREFIX) {
             return Err(());
         }
 
         let size_sig = size;
-        let mut no_overlap = false;
 
         let all_ranges: Vec<Option<Self>> = header[PREFIX_LEN..]
             .split(',')
@@ -158,7 +376,6 @@ impl HttpRange {
                     //    return Err(());
                     //}
                     if start >= size_sig {
-                        no_overlap = true;
                         return Ok(None);
                     }
 
@@ -185,13 +402,8 @@ impl HttpRange {
                 }
             }).collect::<Result<_, _>>()?;
 
-        let ranges: Vec<Self> = all_ranges.into_iter().filter_map(|x| x).collect();
-
-        if no_overlap && ranges.is_empty() {
-            return Err(());
-        }
-
-        Ok(ranges)
+        // Ranges which don't overlap the file are left out, so an empty list means that none of the ranges can be satisfied.
+        Ok(all_ranges.into_iter().filter_map(|x| x).collect())
     }
 }
 
@@ -263,3 +475,47 @@ impl Stream for ChunkedReadFile {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+	use super::{accepted_encodings, coalesce_ranges, HttpRange};
+
+	fn ranges(header: &str, size: u64) -> Vec<(u64, u64)> {
+		HttpRange::parse(header, size).expect("header should be valid").iter().map(|r| (r.start, r.length)).collect()
+	}
+
+	#[test]
+	fn parse_ranges() {
+		assert_eq!(ranges("bytes=0-99", 1000), vec![(0, 100)]);
+		assert_eq!(ranges("bytes=900-", 1000), vec![(900, 100)]);
+		assert_eq!(ranges("bytes=-100", 1000), vec![(900, 100)]);
+		assert_eq!(ranges("bytes=990-2000", 1000), vec![(990, 10)]);
+		assert_eq!(ranges("bytes=0-0, 10-19", 1000), vec![(0, 1), (10, 10)]);
+		assert_eq!(ranges("bytes=1000-", 1000), vec![]);
+		assert!(HttpRange::parse("", 1000).is_err());
+		assert!(HttpRange::parse("items=0-1", 1000).is_err());
+		assert!(HttpRange::parse("bytes=5-1", 1000).is_err());
+	}
+
+	#[test]
+	fn coalesce() {
+		let merged = coalesce_ranges(HttpRange::parse("bytes=50-99, 0-9, 10-19, 5-14, 200-299", 1000).expect("header should be valid"));
+		let merged: Vec<(u64, u64)> = merged.iter().map(|r| (r.start, r.length)).collect();
+		assert_eq!(merged, vec![(0, 20), (50, 50), (200, 100)]);
+
+		let merged = coalesce_ranges(HttpRange::parse("bytes=0-499, 100-199", 1000).expect("header should be valid"));
+		assert_eq!(merged.iter().map(|r| (r.start, r.length)).collect::<Vec<_>>(), vec![(0, 500)]);
+	}
+
+	#[test]
+	fn encodings() {
+		assert_eq!(accepted_encodings(""), vec!["identity"]);
+		assert_eq!(accepted_encodings("gzip, br"), vec!["br", "gzip", "identity"]);
+		assert_eq!(accepted_encodings("GZIP"), vec!["gzip", "identity"]);
+		assert_eq!(accepted_encodings("gzip;q=1.0, br;q=0.5, zstd;q=0.8"), vec!["gzip", "zstd", "br", "identity"]);
+		assert_eq!(accepted_encodings("*"), vec!["br", "zstd", "gzip", "identity"]);
+		assert_eq!(accepted_encodings("br;q=0, *;q=0.5"), vec!["zstd", "gzip", "identity"]);
+		assert_eq!(accepted_encodings("identity, gzip;q=0.5"), vec!["identity", "gzip"]);
+		assert_eq!(accepted_encodings("gzip, identity;q=0"), vec!["gzip"]);
+	}
+}
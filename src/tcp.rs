@@ -0,0 +1,735 @@
+// Tcp.rs handles layer 4 proxying, which passes TLS connections through to upstream servers based on their SNI, and forwards [[stream]] ports to upstream servers. It also handles receiving and sending PROXY protocol headers.
+extern crate actix;
+extern crate actix_connect;
+extern crate actix_http;
+extern crate actix_server;
+extern crate actix_server_config;
+extern crate actix_service;
+extern crate actix_web;
+extern crate futures;
+extern crate tokio_io;
+extern crate tokio_tcp;
+
+use std::{cell::{Cell, RefCell}, cmp, mem, io::{self, Read, Write}, net::{self, IpAddr, Ipv6Addr, SocketAddr, ToSocketAddrs}, rc::Rc, time::{Duration, Instant}};
+use futures::{Async, Future, Poll, future::{self, Either, FutureResult}, task::AtomicTask};
+use self::actix::clock::Delay;
+use self::actix_connect::{default_connector, Connect, Connection, ConnectError};
+use self::actix_http::{Error, HttpService, Request};
+use self::actix_server::{Server, ssl::{RustlsAcceptor, SslError}};
+use self::actix_server_config::{Io, IoStream, ServerConfig};
+use self::actix_service::{NewService, Service, ServiceExt};
+use self::tokio_io::{AsyncRead, AsyncWrite, io::{copy, shutdown, write_all}};
+use self::tokio_tcp::TcpStream;
+use actix_web::{web, App, http::Uri};
+use rustls::ServerConfig as TlsConfig;
+use {body_limit, body_too_large, hsts, index, rc, upload, CONFM};
+
+// How long a client has to send its PROXY protocol header or TLS ClientHello (in seconds), before the connection is closed.
+const PEEK_TIMEOUT: u64 = 10;
+
+// The largest ClientHello or PROXY protocol header that is read. A TLS record (including its header) can't be larger than this.
+const MAX_HELLO: usize = 16_389;
+
+// How much of a connection is read at first. Most ClientHellos fit in this, so the buffer only grows towards MAX_HELLO for the ones that don't.
+const PEEK_SIZE: usize = 1024;
+
+// The most TLS handshakes each worker handles at once, including reading the SNI of the connection. This is the same limit actix's HttpServer used by default.
+const MAX_HANDSHAKES: usize = 256;
+
+// The signature which starts every version 2 PROXY protocol header.
+const PROXY_V2_SIG: [u8; 12] = [0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A];
+
+/* Start a TLS listener which can read a PROXY protocol header and the SNI of each connection before deciding how to handle it. Connections for a [[passthrough]] name are passed through to its upstream server without being decrypted.
+All other connections are handled like a normal HTTPS listener. If proxy_protocol is false, PROXY protocol headers aren't accepted from any source. */
+pub fn tls_server(lst: net::TcpListener, mut tls: TlsConfig, keep_alive: usize, proxy_protocol: bool) -> io::Result<Server> {
+	tls.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+	let acceptor = RustlsAcceptor::new(tls);
+
+	Ok(Server::build().listen("katwebx-tls", lst, move || {
+		// Connections stop counting towards the worker's handshake limit once the TLS acceptor is done with them.
+		Front::new(acceptor.clone().map(|mut io| {
+			io.get_mut().get_mut().0.handshake = None;
+			io
+		}).map_err(SslError::Ssl).and_then(
+			HttpService::build()
+				.keep_alive(keep_alive)
+				.expect(Expect)
+				.finish(App::new().route("/*", web::to(index)))
+				.map_err(SslError::Service)
+				.map_init_err(|_| ())
+		), proxy_protocol, true)
+	})?.start())
+}
+
+// Start an HTTP listener, which can read a PROXY protocol header from trusted sources before handling each connection.
+pub fn http_server(lst: net::TcpListener, keep_alive: usize, proxy_protocol: bool) -> io::Result<Server> {
+	Ok(Server::build().listen("katwebx-http", lst, move || {
+		Front::new(HttpService::build()
+			.keep_alive(keep_alive)
+			.expect(Expect)
+			.finish(App::new().route("/*", web::to(hsts)))
+			.map_init_err(|_| ()), proxy_protocol, false)
+	})?.start())
+}
+
+/* Expect answers requests which have an "Expect: 100-continue" header. Uploads which are larger than the body size limit are rejected with a 413 error, instead of the client being told to send the body.
+Other requests are passed on, and the client is told to continue. */
+#[derive(Clone, Copy)]
+struct Expect;
+
+impl NewService for Expect {
+	type Request = Request;
+	type Response = Request;
+	type Error = Error;
+	type Config = ServerConfig;
+	type Service = Self;
+	type InitError = ();
+	type Future = FutureResult<Self, ()>;
+
+	fn new_service(&self, _: &ServerConfig) -> Self::Future {
+		future::ok(*self)
+	}
+}
+
+impl Service for Expect {
+	type Request = Request;
+	type Response = Request;
+	type Error = Error;
+	type Future = FutureResult<Request, Error>;
+
+	fn poll_ready(&mut self) -> Poll<(), Error> {
+		Ok(Async::Ready(()))
+	}
+
+	fn call(&mut self, req: Request) -> Self::Future {
+		let conf = rc(&CONFM);
+		if upload::too_large(req.headers(), body_limit(req.head(), &conf)) {
+			return future::err(Error::from(body_too_large(conf.smaller_default)))
+		}
+		future::ok(req)
+	}
+}
+
+// Start listeners for [[stream]] sections, which forward every connection on a port to an upstream server. None is returned if there are no [[stream]] sections.
+pub fn stream_server(streams: &[(String, String, Option<u8>)]) -> io::Result<Option<Server>> {
+	if streams.is_empty() {
+		return Ok(None)
+	}
+
+	let mut builder = Server::build();
+	for (listen, dest, send) in streams {
+		let forward = Forward(dest.to_owned(), *send);
+		builder = builder.bind(["katwebx-stream-", listen].concat(), listen, move || forward.to_owned())?;
+	}
+	Ok(Some(builder.start()))
+}
+
+/* Connect to an upstream server, and copy data between it and the client until both of them have closed the connection.
+If a PROXY protocol version is provided, a header containing the client's address is sent to the upstream server first. */
+fn splice(client: ClientStream, dest: &str, send: Option<u8>) -> Box<Future<Item=(), Error=()>> {
+	let addr = match dest.to_socket_addrs().ok().and_then(|mut a| a.next()) {
+		Some(a) => a,
+		None => {
+			println!("[Warn]: Unable to resolve layer 4 upstream {}!", dest);
+			return Box::new(future::err(()))
+		},
+	};
+
+	let header = match (send, client.addr()) {
+		(Some(version), Some(source)) => proxy_header(version, source, addr),
+		_ => Vec::new(),
+	};
+	let dest = dest.to_owned();
+	Box::new(TcpStream::connect(&addr).and_then(|server| write_all(server, header)).and_then(|(server, _)| {
+		let (client_read, client_write) = client.split();
+		let (server_read, server_write) = server.split();
+		let upload = copy(client_read, server_write).and_then(|(_, _, w)| shutdown(w));
+		let download = copy(server_read, client_write).and_then(|(_, _, w)| shutdown(w));
+		upload.join(download)
+	}).map(|_| ()).map_err(move |err| {
+		println!("[Warn]: Layer 4 proxy to {} failed: {}", dest, err)
+	}))
+}
+
+// Forward passes every connection it is given to an upstream server, optionally sending a PROXY protocol header first.
+#[derive(Clone)]
+struct Forward(String, Option<u8>);
+
+impl NewService for Forward {
+	type Request = Io<TcpStream>;
+	type Response = ();
+	type Error = ();
+	type Config = ServerConfig;
+	type Service = Self;
+	type InitError = ();
+	type Future = FutureResult<Self, ()>;
+
+	fn new_service(&self, _: &ServerConfig) -> Self::Future {
+		future::ok(self.to_owned())
+	}
+}
+
+impl Service for Forward {
+	type Request = Io<TcpStream>;
+	type Response = ();
+	type Error = ();
+	type Future = Box<Future<Item=(), Error=()>>;
+
+	fn poll_ready(&mut self) -> Poll<(), ()> {
+		Ok(Async::Ready(()))
+	}
+
+	fn call(&mut self, req: Io<TcpStream>) -> Self::Future {
+		splice(ClientStream::new(req.into_parts().0), &self.0, self.1)
+	}
+}
+
+/* Front wraps the TLS acceptor and HTTP service of a listener. It reads PROXY protocol headers sent by trusted sources, so that the HTTP service sees the real client's address.
+On TLS listeners, it also reads the SNI of each connection, so that connections for [[passthrough]] names never reach the TLS acceptor, and limits how many connections can be handshaking at once. */
+struct Front<S> {
+	inner: S,
+	proxy_protocol: bool,
+	sni: bool,
+}
+
+impl<S> Front<S> {
+	fn new(inner: S, proxy_protocol: bool, sni: bool) -> Self {
+		Self { inner, proxy_protocol, sni }
+	}
+}
+
+impl<S> NewService for Front<S> where S: NewService<Config=ServerConfig, Request=Io<ClientStream>>, S::Service: 'static, S::Future: 'static {
+	type Request = Io<TcpStream>;
+	type Response = ();
+	type Error = ();
+	type Config = ServerConfig;
+	type Service = FrontService<S::Service>;
+	type InitError = S::InitError;
+	type Future = Box<Future<Item=Self::Service, Error=S::InitError>>;
+
+	fn new_service(&self, cfg: &ServerConfig) -> Self::Future {
+		let (proxy_protocol, sni) = (self.proxy_protocol, self.sni);
+		Box::new(self.inner.new_service(cfg).map(move |inner| FrontService { inner: Rc::new(RefCell::new(inner)), proxy_protocol, sni, handshakes: Rc::new(Handshakes { count: Cell::new(0), task: AtomicTask::new() }) }))
+	}
+}
+
+struct FrontService<S> {
+	inner: Rc<RefCell<S>>,
+	proxy_protocol: bool,
+	sni: bool,
+	handshakes: Rc<Handshakes>,
+}
+
+impl<S> Service for FrontService<S> where S: Service<Request=Io<ClientStream>> + 'static, S::Future: 'static {
+	type Request = Io<TcpStream>;
+	type Response = ();
+	type Error = ();
+	type Future = Box<Future<Item=(), Error=()>>;
+
+	// New connections aren't accepted while the worker is at its handshake limit. The worker is woken once one of the handshakes finishes.
+	fn poll_ready(&mut self) -> Poll<(), ()> {
+		if self.sni && self.handshakes.count.get() >= MAX_HANDSHAKES {
+			self.handshakes.task.register();
+			return Ok(Async::NotReady)
+		}
+		self.inner.borrow_mut().poll_ready().map_err(|_| ())
+	}
+
+	// The trusted sources and passthrough names are read from the active config for each connection, so that they can be changed by reloading the config.
+	fn call(&mut self, req: Io<TcpStream>) -> Self::Future {
+		let (inner, sni) = (self.inner.to_owned(), self.sni);
+		let io = req.into_parts().0;
+		let handshake = if sni {Some(Handshake::new(&self.handshakes))} else {None};
+
+		// Only trusted sources can send a PROXY protocol header, as anyone else could use one to pretend to be a different client.
+		let client = if self.proxy_protocol && trusted(&io) {
+			Either::A(read_proxy_header(ClientStream::new(io)))
+		} else {
+			Either::B(future::ok(ClientStream::new(io)))
+		};
+
+		// Connections which aren't TLS, or have a ClientHello which can't be parsed, are left for the TLS acceptor to reject.
+		let client = client.and_then(move |client| {
+			if !sni {
+				return Either::B(future::ok((client, None)))
+			}
+			Either::A(Peek::new(client, parse_sni).map(move |(mut client, name)| {
+				client.handshake = handshake;
+				(client, name.and_then(|n| n))
+			}))
+		});
+
+		Box::new(client.map_err(|_| ()).and_then(move |(mut client, name)| {
+			match name.and_then(|n| rc(&CONFM).passthrough.get(&n).cloned()) {
+				Some((dest, send)) => {
+					client.handshake = None;
+					Either::A(splice(client, &dest, send))
+				},
+				None => Either::B(inner.borrow_mut().call(Io::new(client)).map(|_| ()).map_err(|_| ())),
+			}
+		}))
+	}
+}
+
+// Handshakes counts the connections of a worker which are still handshaking, so that the worker can stop accepting new connections while it is at MAX_HANDSHAKES.
+struct Handshakes {
+	count: Cell<usize>,
+	task: AtomicTask,
+}
+
+// Handshake marks a connection as handshaking, until it is dropped.
+struct Handshake(Rc<Handshakes>);
+
+impl Handshake {
+	fn new(handshakes: &Rc<Handshakes>) -> Self {
+		handshakes.count.set(handshakes.count.get() + 1);
+		Self(handshakes.to_owned())
+	}
+}
+
+impl Drop for Handshake {
+	fn drop(&mut self) {
+		self.0.count.set(self.0.count.get() - 1);
+		self.0.task.notify();
+	}
+}
+
+// Check if a connection comes from a source which is allowed to send PROXY protocol headers.
+fn trusted(io: &TcpStream) -> bool {
+	io.peer_addr().ok().map_or(false, |addr| {
+		let ip = addr.ip().to_string();
+		rc(&CONFM).proxy_protocol_sources.iter().any(|s| *s == ip)
+	})
+}
+
+// Read a PROXY protocol header from the start of a connection. Connections which don't start with a header are handled normally.
+fn read_proxy_header(client: ClientStream) -> Box<Future<Item=ClientStream, Error=io::Error>> {
+	Box::new(Peek::new(client, parse_proxy_header).and_then(|(mut client, header)| {
+		match header {
+			Some((len, peer)) => {
+				client.buf = client.buf.split_off(len);
+				client.peer = peer;
+				Ok(client)
+			},
+			None => Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid PROXY protocol header")),
+		}
+	}))
+}
+
+/* ClientStream is a client's connection, along with the client's address if it was sent in a PROXY protocol header.
+The address is used as the connection's peer address, so that it is used for logging, authentication and forwarding headers.
+Data which was read from the connection to find its PROXY protocol header or SNI is kept, and read again before the rest of the connection. On TLS listeners, the stream also holds its Handshake until the TLS acceptor is done with it. */
+pub struct ClientStream {
+	io: TcpStream,
+	peer: Option<SocketAddr>,
+	buf: Vec<u8>,
+	handshake: Option<Handshake>,
+}
+
+impl ClientStream {
+	fn new(io: TcpStream) -> Self {
+		Self { io, peer: None, buf: Vec::new(), handshake: None }
+	}
+
+	fn addr(&self) -> Option<SocketAddr> {
+		self.peer.or_else(|| self.io.peer_addr().ok())
+	}
+}
+
+impl Read for ClientStream {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		if self.buf.is_empty() {
+			return self.io.read(buf)
+		}
+
+		let len = cmp::min(buf.len(), self.buf.len());
+		buf[..len].copy_from_slice(&self.buf[..len]);
+		self.buf = self.buf.split_off(len);
+		Ok(len)
+	}
+}
+
+impl Write for ClientStream {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.io.write(buf)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.io.flush()
+	}
+}
+
+impl AsyncRead for ClientStream {}
+
+impl AsyncWrite for ClientStream {
+	fn shutdown(&mut self) -> Poll<(), io::Error> {
+		AsyncWrite::shutdown(&mut self.io)
+	}
+}
+
+impl IoStream for ClientStream {
+	fn peer_addr(&self) -> Option<SocketAddr> {
+		self.addr()
+	}
+
+	fn set_nodelay(&mut self, nodelay: bool) -> io::Result<()> {
+		self.io.set_nodelay(nodelay)
+	}
+
+	fn set_linger(&mut self, dur: Option<Duration>) -> io::Result<()> {
+		self.io.set_linger(dur)
+	}
+
+	fn set_keepalive(&mut self, dur: Option<Duration>) -> io::Result<()> {
+		self.io.set_keepalive(dur)
+	}
+}
+
+/* Create a connector which sends a PROXY protocol header containing the client's address to upstream servers, before anything else is sent.
+Like certs::sni_connector, TLS connections can also be made to use a different server name than the upstream's host. */
+pub fn proxy_connector(version: u8, source: SocketAddr, name: Option<Uri>) -> impl Service<Request=Connect<Uri>, Response=Connection<Uri, TcpStream>, Error=ConnectError> + Clone {
+	default_connector().and_then(SendHeader { version, source, name })
+}
+
+#[derive(Clone)]
+struct SendHeader {
+	version: u8,
+	source: SocketAddr,
+	name: Option<Uri>,
+}
+
+impl Service for SendHeader {
+	type Request = Connection<Uri, TcpStream>;
+	type Response = Connection<Uri, TcpStream>;
+	type Error = ConnectError;
+	type Future = Box<Future<Item=Connection<Uri, TcpStream>, Error=ConnectError>>;
+
+	fn poll_ready(&mut self) -> Poll<(), ConnectError> {
+		Ok(Async::Ready(()))
+	}
+
+	fn call(&mut self, conn: Connection<Uri, TcpStream>) -> Self::Future {
+		let (io, uri) = conn.into_parts();
+		let header = match io.peer_addr() {
+			Ok(dest) => proxy_header(self.version, self.source, dest),
+			Err(err) => return Box::new(future::err(ConnectError::Io(err))),
+		};
+		let name = self.name.to_owned().unwrap_or(uri);
+		Box::new(write_all(io, header).map(move |(io, _)| Connection::new(io, name)).map_err(ConnectError::Io))
+	}
+}
+
+// Build a PROXY protocol header for a connection from source to dest. If only one of the addresses is IPv6, both are sent as IPv6 addresses.
+pub fn proxy_header(version: u8, source: SocketAddr, dest: SocketAddr) -> Vec<u8> {
+	let (src, dst) = match (source.ip(), dest.ip()) {
+		(IpAddr::V4(s), IpAddr::V4(d)) => (IpAddr::V4(s), IpAddr::V4(d)),
+		(s, d) => (IpAddr::V6(to_ipv6(s)), IpAddr::V6(to_ipv6(d))),
+	};
+
+	if version == 1 {
+		let family = if src.is_ipv4() {"TCP4"} else {"TCP6"};
+		return ["PROXY ", family, " ", &src.to_string(), " ", &dst.to_string(), " ", &source.port().to_string(), " ", &dest.port().to_string(), "\r\n"].concat().into_bytes()
+	}
+
+	let mut header = PROXY_V2_SIG.to_vec();
+	header.push(0x21); // Version 2, PROXY command.
+	match (src, dst) {
+		(IpAddr::V4(s), IpAddr::V4(d)) => {
+			header.extend_from_slice(&[0x11, 0, 12]);
+			header.extend_from_slice(&s.octets());
+			header.extend_from_slice(&d.octets());
+		},
+		(s, d) => {
+			header.extend_from_slice(&[0x21, 0, 36]);
+			header.extend_from_slice(&to_ipv6(s).octets());
+			header.extend_from_slice(&to_ipv6(d).octets());
+		},
+	}
+	header.extend_from_slice(&source.port().to_be_bytes());
+	header.extend_from_slice(&dest.port().to_be_bytes());
+	header
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+	match ip {
+		IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+		IpAddr::V6(ip) => ip,
+	}
+}
+
+/* Get the length of the PROXY protocol header at the start of a connection, and the client address it contains. None is returned if more data is needed.
+Connections which don't start with a header have a length of 0, and headers without a client address (such as health checks) don't have an address. */
+fn parse_proxy_header(buf: &[u8]) -> Option<(usize, Option<SocketAddr>)> {
+	if starts_like(buf, &PROXY_V2_SIG) {
+		return parse_proxy_v2(buf)
+	}
+	if !starts_like(buf, b"PROXY ") {
+		return Some((0, None))
+	}
+
+	// Version 1 headers are a single line of text, which can't be longer than 107 bytes.
+	let end = match buf.iter().take(107).position(|b| *b == b'\n') {
+		Some(i) => i + 1,
+		None if buf.len() < 107 => return None,
+		None => return Some((0, None)),
+	};
+	let line = String::from_utf8_lossy(&buf[..end]);
+	let parts: Vec<&str> = line.trim_end().split(' ').collect();
+	let peer = match parts.as_slice() {
+		[_, "TCP4", src, _, port, _] | [_, "TCP6", src, _, port, _] => src.parse::<IpAddr>().ok().and_then(|ip| port.parse::<u16>().ok().map(|p| SocketAddr::new(ip, p))),
+		_ => None,
+	};
+	Some((end, peer))
+}
+
+fn parse_proxy_v2(buf: &[u8]) -> Option<(usize, Option<SocketAddr>)> {
+	let mut header = Reader(buf);
+	header.take(PROXY_V2_SIG.len())?;
+	let command = header.u8()?;
+	let family = header.u8()?;
+	let len = header.u16()?;
+	let mut addrs = Reader(header.take(len)?);
+	if command >> 4 != 2 {
+		return Some((0, None))
+	}
+	if command & 0xF != 1 {
+		return Some((16 + len, None))
+	}
+
+	// The addresses are followed by the source and destination ports.
+	let peer = match family >> 4 {
+		1 => addrs.take(12).map(|a| SocketAddr::from(([a[0], a[1], a[2], a[3]], u16::from_be_bytes([a[8], a[9]])))),
+		2 => addrs.take(36).map(|a| {
+			let mut ip = [0; 16];
+			ip.copy_from_slice(&a[..16]);
+			SocketAddr::from((ip, u16::from_be_bytes([a[32], a[33]])))
+		}),
+		_ => None,
+	};
+	Some((16 + len, peer))
+}
+
+// Check if a buffer starts with a prefix, or could once more data arrives.
+fn starts_like(buf: &[u8], prefix: &[u8]) -> bool {
+	let len = buf.len().min(prefix.len());
+	buf[..len] == prefix[..len]
+}
+
+/* Peek reads the start of a new connection until it can be parsed, keeping the data in the connection's buffer so that it can still be handled by the TLS acceptor.
+Reading the data, instead of peeking at it, means that the connection is only checked again once more data has arrived. None is returned if the data can't be parsed. */
+struct Peek<T> {
+	client: Option<ClientStream>,
+	buf: Vec<u8>,
+	len: usize,
+	parse: fn(&[u8]) -> Option<T>,
+	timeout: Delay,
+}
+
+impl<T> Peek<T> {
+	fn new(mut client: ClientStream, parse: fn(&[u8]) -> Option<T>) -> Self {
+		let buf = mem::replace(&mut client.buf, Vec::new());
+		Self { client: Some(client), len: buf.len(), buf, parse, timeout: Delay::new(Instant::now() + Duration::from_secs(PEEK_TIMEOUT)) }
+	}
+
+	// Give back the connection, with the data which was read put back into its buffer.
+	fn finish(&mut self, res: Option<T>) -> (ClientStream, Option<T>) {
+		let mut client = self.client.take().expect("Use after completion");
+		self.buf.truncate(self.len);
+		client.buf = mem::replace(&mut self.buf, Vec::new());
+		(client, res)
+	}
+}
+
+impl<T> Future for Peek<T> {
+	type Item = (ClientStream, Option<T>);
+	type Error = io::Error;
+
+	fn poll(&mut self) -> Poll<(ClientStream, Option<T>), io::Error> {
+		loop {
+			if let Some(res) = (self.parse)(&self.buf[..self.len]) {
+				return Ok(Async::Ready(self.finish(Some(res))))
+			}
+			if self.len >= MAX_HELLO {
+				return Ok(Async::Ready(self.finish(None)))
+			}
+			if self.len == self.buf.len() {
+				let size = cmp::min(cmp::max(self.len * 4, PEEK_SIZE), MAX_HELLO);
+				self.buf.resize(size, 0);
+			}
+
+			match self.client.as_mut().expect("Use after completion").io.poll_read(&mut self.buf[self.len..])? {
+				Async::Ready(0) => return Ok(Async::Ready(self.finish(None))),
+				Async::Ready(len) => self.len += len,
+				Async::NotReady => return match self.timeout.poll() {
+					Ok(Async::Ready(())) => Err(io::Error::new(io::ErrorKind::TimedOut, "The client didn't send enough data in time")),
+					_ => Ok(Async::NotReady),
+				},
+			}
+		}
+	}
+}
+
+/* Get the server name from the start of a TLS connection. None is returned if more data is needed, and Some(None) is returned if the connection doesn't have a server name.
+Only the first TLS record is checked, which always contains the server name in practice. */
+fn parse_sni(buf: &[u8]) -> Option<Option<String>> {
+	if !buf.is_empty() && buf[0] != 0x16 {
+		return Some(None)
+	}
+	if buf.len() < 5 {
+		return None
+	}
+
+	let len = (usize::from(buf[3]) << 8) | usize::from(buf[4]);
+	if buf.len() < 5 + len {
+		return None
+	}
+	Some(hello_sni(&buf[5..5+len]))
+}
+
+// Find the server name extension in a ClientHello handshake message.
+fn hello_sni(msg: &[u8]) -> Option<String> {
+	let mut hello = Reader(msg);
+	if hello.u8()? != 1 {
+		return None
+	}
+	hello.take(3 + 2 + 32)?; // Handshake length, version and random.
+	let len = usize::from(hello.u8()?);
+	hello.take(len)?; // Session ID.
+	let len = hello.u16()?;
+	hello.take(len)?; // Cipher suites.
+	let len = usize::from(hello.u8()?);
+	hello.take(len)?; // Compression methods.
+
+	let len = hello.u16()?;
+	let mut extensions = Reader(hello.take(len)?);
+	while let (Some(kind), Some(len)) = (extensions.u16(), extensions.u16()) {
+		let data = extensions.take(len)?;
+		if kind != 0 {
+			continue
+		}
+
+		let mut list = Reader(data);
+		let len = list.u16()?;
+		let mut names = Reader(list.take(len)?);
+		while let Some(kind) = names.u8() {
+			let len = names.u16()?;
+			let name = names.take(len)?;
+			if kind == 0 {
+				return String::from_utf8(name.to_vec()).ok().map(|n| n.to_lowercase())
+			}
+		}
+	}
+	None
+}
+
+// Reader reads big-endian values from a TLS message or PROXY protocol header.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+	fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+		if self.0.len() < len {
+			return None
+		}
+		let (data, rest) = self.0.split_at(len);
+		self.0 = rest;
+		Some(data)
+	}
+
+	fn u8(&mut self) -> Option<u8> {
+		self.take(1).map(|b| b[0])
+	}
+
+	fn u16(&mut self) -> Option<usize> {
+		self.take(2).map(|b| (usize::from(b[0]) << 8) | usize::from(b[1]))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{parse_proxy_header, parse_sni, proxy_header};
+	use std::net::SocketAddr;
+
+	// Build a TLS record containing a ClientHello, with an unrelated extension before the server name. The lengths are always small enough to fit in a u16.
+	#[allow(clippy::cast_possible_truncation)]
+	fn client_hello(name: Option<&str>) -> Vec<u8> {
+		let mut extensions = vec![0, 10, 0, 4, 0, 2, 0, 29];
+		if let Some(name) = name {
+			let len = name.len() as u16;
+			extensions.extend_from_slice(&[0, 0]);
+			extensions.extend_from_slice(&(len + 5).to_be_bytes());
+			extensions.extend_from_slice(&(len + 3).to_be_bytes());
+			extensions.push(0);
+			extensions.extend_from_slice(&len.to_be_bytes());
+			extensions.extend_from_slice(name.as_bytes());
+		}
+
+		let mut body = vec![3, 3];
+		body.extend_from_slice(&[0; 32]);
+		body.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+		body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+		body.extend_from_slice(&extensions);
+
+		let mut record = vec![0x16, 3, 1];
+		record.extend_from_slice(&(body.len() as u16 + 4).to_be_bytes());
+		record.extend_from_slice(&[1, 0]);
+		record.extend_from_slice(&(body.len() as u16).to_be_bytes());
+		record.extend_from_slice(&body);
+		record
+	}
+
+	#[test]
+	fn sni() {
+		assert_eq!(parse_sni(&client_hello(Some("Example.com"))), Some(Some("example.com".to_owned())));
+		assert_eq!(parse_sni(&client_hello(None)), Some(None));
+	}
+
+	#[test]
+	fn incomplete_or_not_tls() {
+		let hello = client_hello(Some("example.com"));
+		assert_eq!(parse_sni(&hello[..hello.len() - 1]), None);
+		assert_eq!(parse_sni(&hello[..3]), None);
+		assert_eq!(parse_sni(b""), None);
+		assert_eq!(parse_sni(b"GET / HTTP/1.1\r\n"), Some(None));
+	}
+
+	fn addr(s: &str) -> SocketAddr {
+		s.parse().expect("address should be valid")
+	}
+
+	#[test]
+	fn proxy_v1() {
+		let header = proxy_header(1, addr("192.0.2.1:5000"), addr("198.51.100.2:443"));
+		assert_eq!(header, b"PROXY TCP4 192.0.2.1 198.51.100.2 5000 443\r\n".to_vec());
+		assert_eq!(parse_proxy_header(&header), Some((header.len(), Some(addr("192.0.2.1:5000")))));
+
+		let header = proxy_header(1, addr("[2001:db8::1]:5000"), addr("198.51.100.2:443"));
+		assert_eq!(header, b"PROXY TCP6 2001:db8::1 ::ffff:198.51.100.2 5000 443\r\n".to_vec());
+		assert_eq!(parse_proxy_header(&header), Some((header.len(), Some(addr("[2001:db8::1]:5000")))));
+
+		assert_eq!(parse_proxy_header(b"PROXY UNKNOWN\r\nGET /"), Some((15, None)));
+		assert_eq!(parse_proxy_header(b"PROXY TCP4 192.0.2.1"), None);
+	}
+
+	#[test]
+	fn proxy_v2() {
+		let header = proxy_header(2, addr("192.0.2.1:5000"), addr("198.51.100.2:443"));
+		assert_eq!(header.len(), 28);
+		assert_eq!(parse_proxy_header(&header), Some((28, Some(addr("192.0.2.1:5000")))));
+		assert_eq!(parse_proxy_header(&header[..20]), None);
+
+		let header = proxy_header(2, addr("[2001:db8::1]:5000"), addr("[2001:db8::2]:443"));
+		assert_eq!(header.len(), 52);
+		assert_eq!(parse_proxy_header(&header), Some((52, Some(addr("[2001:db8::1]:5000")))));
+
+		// LOCAL commands, which are used for health checks, don't have a client address.
+		let mut local = header[..12].to_vec();
+		local.extend_from_slice(&[0x20, 0, 0, 0]);
+		assert_eq!(parse_proxy_header(&local), Some((16, None)));
+	}
+
+	#[test]
+	fn no_proxy_header() {
+		assert_eq!(parse_proxy_header(b"GET / HTTP/1.1\r\n"), Some((0, None)));
+		assert_eq!(parse_proxy_header(b"\x16\x03\x01"), Some((0, None)));
+	}
+}
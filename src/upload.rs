@@ -0,0 +1,123 @@
+// Upload.rs handles limiting the size of request bodies, and buffering them to the disk before they are sent to an upstream server.
+extern crate actix_web;
+extern crate futures;
+extern crate bytes;
+
+use futures::{Async, Future, Poll, Stream, future};
+use bytes::Bytes;
+use std::{cell::Cell, env, fs::{self, OpenOptions}, io::{self, Write}, path::PathBuf, process, rc::Rc, sync::atomic::{AtomicUsize, Ordering}};
+use actix_web::{error::{BlockingError, PayloadError}, http::{header, header::HeaderMap}, web};
+use stream::ChunkedReadFile;
+
+// A request body, which may have been limited or buffered.
+pub type Upload = Box<Stream<Item=Bytes, Error=PayloadError>>;
+
+// Used to give each buffered request body a unique file name.
+static SPOOLED: AtomicUsize = AtomicUsize::new(0);
+
+// Check if a request's Content-Length is larger than the limit. A limit of 0 means there is no limit.
+pub fn too_large(headers: &HeaderMap, limit: u64) -> bool {
+	limit != 0 && headers.get(header::CONTENT_LENGTH).and_then(|h| h.to_str().ok()).and_then(|h| h.parse::<u64>().ok()).map_or(false, |len| len > limit)
+}
+
+/* Limit a request body to the specified number of bytes. Bodies which are longer than the limit end with an error, and the returned flag is set so that a 413 error can be sent instead of an upstream error.
+A limit of 0 means there is no limit. */
+pub fn limit<S: Stream<Item=Bytes, Error=PayloadError> + 'static>(body: S, limit: u64) -> (Upload, Rc<Cell<bool>>) {
+	let exceeded = Rc::new(Cell::new(false));
+	if limit == 0 {
+		return (Box::new(body), exceeded)
+	}
+	(Box::new(Limit { stream: body, limit, received: 0, exceeded: exceeded.to_owned() }), exceeded)
+}
+
+struct Limit<S> {
+	stream: S,
+	limit: u64,
+	received: u64,
+	exceeded: Rc<Cell<bool>>,
+}
+
+impl<S: Stream<Item=Bytes, Error=PayloadError>> Stream for Limit<S> {
+	type Item = Bytes;
+	type Error = PayloadError;
+
+	fn poll(&mut self) -> Poll<Option<Bytes>, PayloadError> {
+		let res = self.stream.poll()?;
+		if let Async::Ready(Some(ref chunk)) = res {
+			self.received += chunk.len() as u64;
+			if self.received > self.limit {
+				self.exceeded.set(true);
+				return Err(PayloadError::Overflow)
+			}
+		}
+		Ok(res)
+	}
+}
+
+/* Read an entire request body into a temporary file, and then stream it back from the file in chunks of the specified size.
+The upstream server isn't contacted until the whole body has been received, so slow clients don't keep upstream connections busy. */
+pub fn spool(body: Upload, chunk_size: u64) -> Box<Future<Item=Upload, Error=PayloadError>> {
+	let path = env::temp_dir().join(["katwebx-upload-", &process::id().to_string(), "-", &SPOOLED.fetch_add(1, Ordering::Relaxed).to_string()].concat());
+	let file = match OpenOptions::new().read(true).write(true).create_new(true).open(&path) {
+		Ok(f) => f,
+		Err(err) => return Box::new(future::err(PayloadError::Io(err))),
+	};
+
+	// The file is removed when the guard is dropped, even if the request is dropped while the body is still being buffered.
+	let temp = TempFile(path);
+	Box::new(body.fold((file, 0), |(mut file, size), chunk| {
+		web::block(move || file.write_all(&chunk).map(|_| (file, size + chunk.len() as u64))).map_err(blocking_error)
+	}).then(move |res| {
+		let (file, size) = res?;
+		let stream = ChunkedReadFile {
+			offset: 0,
+			size,
+			file: Some(file),
+			fut: None,
+			counter: 0,
+			chunk_size,
+		};
+		Ok(Box::new(Spooled { stream: Some(stream), _temp: temp }) as Upload)
+	}))
+}
+
+fn blocking_error(err: BlockingError<io::Error>) -> PayloadError {
+	match err {
+		web::BlockingError::Error(err) => PayloadError::Io(err),
+		web::BlockingError::Canceled => PayloadError::Io(io::Error::new(io::ErrorKind::Other, "Unable to buffer the request body")),
+	}
+}
+
+// TempFile removes a temporary file once it is dropped.
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+	fn drop(&mut self) {
+		let _ = fs::remove_file(&self.0);
+	}
+}
+
+// Spooled streams a buffered request body from its temporary file, and removes the file once it is dropped.
+struct Spooled {
+	stream: Option<ChunkedReadFile>,
+	_temp: TempFile,
+}
+
+impl Stream for Spooled {
+	type Item = Bytes;
+	type Error = PayloadError;
+
+	fn poll(&mut self) -> Poll<Option<Bytes>, PayloadError> {
+		match self.stream.as_mut() {
+			Some(s) => s.poll().map_err(|err| PayloadError::Io(io::Error::new(io::ErrorKind::Other, err.to_string()))),
+			None => Ok(Async::Ready(None)),
+		}
+	}
+}
+
+impl Drop for Spooled {
+	fn drop(&mut self) {
+		// The file has to be closed before it can be removed on some platforms, so it's closed before the temporary file guard is dropped.
+		self.stream.take();
+	}
+}
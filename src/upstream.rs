@@ -0,0 +1,729 @@
+// Upstream.rs handles choosing which upstream server a proxied request gets sent to, and connecting to upstream servers listening on Unix sockets.
+
+// Hashes are reduced modulo the pool length before being cast, so this can be ignored.
+#![allow(clippy::cast_possible_truncation)]
+
+extern crate actix;
+extern crate actix_web;
+extern crate webpki;
+#[cfg(unix)]
+extern crate actix_connect;
+#[cfg(unix)]
+extern crate actix_service;
+#[cfg(unix)]
+extern crate tokio_uds;
+extern crate futures;
+use std::{sync::{Arc, Mutex, atomic::{AtomicBool, AtomicUsize, Ordering}}, hash::{Hash, Hasher}, collections::hash_map::DefaultHasher, net::{TcpStream, ToSocketAddrs}, io::{self, Read, Write}, time::{Duration, Instant}, thread};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream as StdUnixStream;
+use self::actix::clock::Delay;
+use self::webpki::DNSNameRef;
+use rustls::{ClientConfig, ClientSession};
+use actix_web::{client::{ConnectError as ClientConnectError, SendRequestError}, error::PayloadError, http::{header, header::HeaderMap, Method, StatusCode, Uri}};
+use cgi;
+#[cfg(unix)]
+use self::actix_connect::{Connect, Connection, ConnectError};
+#[cfg(unix)]
+use self::actix_service::Service;
+#[cfg(unix)]
+use self::tokio_uds::UnixStream;
+use futures::{Async, Future, Poll, Stream};
+
+/* Split a Unix socket destination (formatted as "unix:/run/app.sock", optionally followed by ":/path/prefix") into the socket's path and the path prefix.
+None is returned if the destination isn't a Unix socket. */
+pub fn unix_socket(dest: &str) -> Option<(&str, &str)> {
+	if !dest.starts_with("unix:") {
+		return None
+	}
+
+	let dest = &dest[5..];
+	match dest.find(':') {
+		Some(i) => Some((&dest[..i], dest[i+1..].trim_end_matches('/'))),
+		None => Some((dest, "")),
+	}
+}
+
+// Get the address of a FastCGI application server from a destination formatted as "fastcgi://127.0.0.1:9000" or "fastcgi://unix:/run/php-fpm.sock". None is returned if the destination isn't a FastCGI server.
+pub fn fastcgi_addr(dest: &str) -> Option<&str> {
+	if dest.starts_with("fastcgi://") {
+		return Some(&dest[10..])
+	}
+	None
+}
+
+// UnixConnector connects to an upstream server over a Unix socket, no matter which host the request is sent to.
+#[cfg(unix)]
+#[derive(Clone)]
+pub struct UnixConnector(Arc<String>);
+
+#[cfg(unix)]
+impl UnixConnector {
+	pub fn new(socket: &str) -> Self {
+		Self(Arc::new(socket.to_owned()))
+	}
+}
+
+#[cfg(unix)]
+impl Service for UnixConnector {
+	type Request = Connect<Uri>;
+	type Response = Connection<Uri, UnixStream>;
+	type Error = ConnectError;
+	type Future = Box<Future<Item=Connection<Uri, UnixStream>, Error=ConnectError>>;
+
+	fn poll_ready(&mut self) -> Poll<(), ConnectError> {
+		Ok(Async::Ready(()))
+	}
+
+	fn call(&mut self, req: Connect<Uri>) -> Self::Future {
+		let uri = req.get_ref().to_owned();
+		Box::new(UnixStream::connect(self.0.as_str()).map(move |io| Connection::new(io, uri)).map_err(ConnectError::Io))
+	}
+}
+
+// The strategy used to pick an upstream server from a pool.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Strategy {
+	RoundRobin,
+	Weighted,
+	LeastInFlight,
+	IpHash,
+}
+
+impl Strategy {
+	pub fn parse(name: &str) -> Option<Self> {
+		match name {
+			"round-robin" => Some(Self::RoundRobin),
+			"weighted" => Some(Self::Weighted),
+			"least-in-flight" => Some(Self::LeastInFlight),
+			"ip-hash" => Some(Self::IpHash),
+			_ => None,
+		}
+	}
+}
+
+/* A rule which sends a slice of a proxy location's requests to a canary instead. Requests are sent to the canary if they have the header or cookie, or if the hash of the client's IP falls within the percentage.
+Header and cookie rules can either require a specific value ("X-Canary: 1" or "canary=1"), or only require the header or cookie to be present. */
+pub struct Canary {
+	header: Option<(String, Option<String>)>,
+	cookie: Option<(String, Option<String>)>,
+	percent: u64,
+}
+
+impl Canary {
+	pub fn new(header: Option<&str>, cookie: Option<&str>, percent: u64) -> Self {
+		let split = |rule: &str, sep: char| {
+			let mut parts = rule.splitn(2, sep);
+			(parts.next().unwrap_or("").trim().to_owned(), parts.next().map(|v| v.trim().to_owned()))
+		};
+
+		Self {
+			header: header.map(|h| split(h, ':')),
+			cookie: cookie.map(|c| split(c, '=')),
+			percent,
+		}
+	}
+
+	// Check if a request should be sent to the canary.
+	pub fn matches(&self, headers: &HeaderMap, client_ip: &str) -> bool {
+		if let Some((name, value)) = &self.header {
+			let mut found = headers.get_all(name.as_str()).iter().filter_map(|h| h.to_str().ok());
+			if found.any(|h| value.as_ref().map_or(true, |v| h.trim() == v)) {
+				return true
+			}
+		}
+
+		if let Some((name, value)) = &self.cookie {
+			let mut cookies = headers.get_all(header::COOKIE).iter().filter_map(|h| h.to_str().ok()).flat_map(|h| h.split(';'));
+			if cookies.any(|c| {
+				let mut parts = c.trim().splitn(2, '=');
+				parts.next() == Some(name.as_str()) && value.as_ref().map_or(true, |v| parts.next() == Some(v.as_str()))
+			}) {
+				return true
+			}
+		}
+
+		// The client's IP is hashed, so that each client consistently uses either the canary or the normal upstreams.
+		if self.percent > 0 {
+			let mut hasher = DefaultHasher::new();
+			client_ip.hash(&mut hasher);
+			return hasher.finish() % 100 < self.percent
+		}
+		false
+	}
+}
+
+/* Settings for retrying failed requests on a different upstream server. on lists the failures which are retried, which can be connect (unable to connect), timeout, reset (the connection failed before a response was received), or a status code.
+Only idempotent requests are retried unless non_idempotent is set, and requests with bodies longer than buffer bytes are never retried. */
+#[derive(Clone)]
+pub struct Retry {
+	pub attempts: usize,
+	pub on: Vec<String>,
+	pub non_idempotent: bool,
+	pub buffer: usize,
+}
+
+impl Retry {
+	// Check if requests with the specified method can be retried.
+	pub fn allows_method(&self, method: &Method) -> bool {
+		self.non_idempotent || [Method::GET, Method::HEAD, Method::OPTIONS, Method::PUT, Method::DELETE, Method::TRACE].contains(method)
+	}
+
+	// Check if a failed request should be retried.
+	pub fn on_error(&self, err: &SendRequestError) -> bool {
+		let class = match err {
+			SendRequestError::Timeout | SendRequestError::Connect(ClientConnectError::Timeout) => "timeout",
+			SendRequestError::Connect(_) => "connect",
+			SendRequestError::Send(_) | SendRequestError::Response(_) => "reset",
+			_ => return false,
+		};
+		self.on.iter().any(|c| c == class)
+	}
+
+	// Check if a response with the specified status should be retried.
+	pub fn on_status(&self, status: StatusCode) -> bool {
+		self.on.iter().any(|c| c == status.as_str())
+	}
+}
+
+/* Timeouts for requests sent to upstream servers. first_byte limits how long the upstream can take to send its response headers, and read limits how long it can go without sending any of the response body.
+If total is set, the entire request (including retries and the response body) must finish within it. */
+#[derive(Clone, Copy)]
+pub struct Timeouts {
+	pub connect: Duration,
+	pub first_byte: Duration,
+	pub read: Duration,
+	pub total: Option<Duration>,
+}
+
+impl Timeouts {
+	// Get the time allowed for receiving the response headers of a request started at the specified time, which is shortened if the total timeout would be reached first.
+	pub fn first_byte(&self, start: Instant) -> Duration {
+		match self.total {
+			Some(total) => total.checked_sub(start.elapsed()).unwrap_or_default().min(self.first_byte),
+			None => self.first_byte,
+		}
+	}
+}
+
+// BodyTimeout ends a response body with an error if the upstream stops sending data for longer than the read timeout, or if the total timeout is reached.
+pub struct BodyTimeout<S> {
+	stream: S,
+	idle: Delay,
+	read: Duration,
+	total: Option<Delay>,
+}
+
+impl<S> BodyTimeout<S> {
+	pub fn new(stream: S, timeouts: Timeouts, start: Instant) -> Self {
+		Self {
+			stream,
+			idle: Delay::new(Instant::now() + timeouts.read),
+			read: timeouts.read,
+			total: timeouts.total.map(|t| Delay::new(start + t)),
+		}
+	}
+}
+
+impl<S: Stream<Error=PayloadError>> Stream for BodyTimeout<S> {
+	type Item = S::Item;
+	type Error = PayloadError;
+
+	fn poll(&mut self) -> Poll<Option<S::Item>, PayloadError> {
+		if let Some(Ok(Async::Ready(()))) = self.total.as_mut().map(Future::poll) {
+			return Err(PayloadError::Io(io::Error::new(io::ErrorKind::TimedOut, "The upstream server didn't finish sending its response in time")))
+		}
+
+		if let Async::Ready(chunk) = self.stream.poll()? {
+			self.idle.reset(Instant::now() + self.read);
+			return Ok(Async::Ready(chunk))
+		}
+
+		match self.idle.poll() {
+			Ok(Async::NotReady) => Ok(Async::NotReady),
+			_ => Err(PayloadError::Io(io::Error::new(io::ErrorKind::TimedOut, "The upstream server stopped sending its response"))),
+		}
+	}
+}
+
+// Settings for actively checking whether the upstream servers in a pool are alive. HTTPS upstreams are checked using the proxy's TLS settings.
+#[derive(Clone)]
+pub struct HealthCheck {
+	pub path: String,
+	pub interval: u64,
+	pub status: u16,
+	pub rise: usize,
+	pub fall: usize,
+	pub tls: Arc<ClientConfig>,
+	pub tls_name: Option<String>,
+}
+
+// Settings for passively detecting failing upstream servers. Once max_fails requests in a row fail, the upstream stops receiving requests for fail_timeout seconds.
+#[derive(Clone, Copy)]
+pub struct Breaker {
+	pub max_fails: usize,
+	pub fail_timeout: u64,
+}
+
+// A single upstream server, along with the number of requests currently being sent to it.
+pub struct Upstream {
+	pub dest: String,
+	weight: usize,
+	in_flight: AtomicUsize,
+	healthy: AtomicBool,
+	breaker: Option<Breaker>,
+	failures: AtomicUsize,
+	opened: Mutex<Option<Instant>>,
+	trial: AtomicBool,
+}
+
+impl Upstream {
+	/* Check if the upstream's circuit breaker allows sending it a request.
+	Once fail_timeout has passed since the breaker opened, it becomes half-open, and a single trial request is allowed through. */
+	fn available(&self) -> bool {
+		let breaker = match self.breaker {
+			Some(b) => b,
+			None => return true,
+		};
+
+		match *self.opened.lock().unwrap_or_else(|e| e.into_inner()) {
+			Some(t) => t.elapsed() >= Duration::from_secs(breaker.fail_timeout) && !self.trial.load(Ordering::SeqCst),
+			None => true,
+		}
+	}
+}
+
+/* InFlight marks a request as being handled by an upstream server, until it is dropped.
+If the request is the trial request of a half-open breaker, it also tracks whether an outcome was recorded for it. */
+pub struct InFlight {
+	upstream: Arc<Upstream>,
+	trial: bool,
+	recorded: AtomicBool,
+}
+
+impl InFlight {
+	fn new(upstream: &Arc<Upstream>, trial: bool) -> Self {
+		upstream.in_flight.fetch_add(1, Ordering::SeqCst);
+		Self {
+			upstream: upstream.to_owned(),
+			trial,
+			recorded: AtomicBool::new(false),
+		}
+	}
+
+	pub fn dest(&self) -> &str {
+		&self.upstream.dest
+	}
+
+	// Record that the upstream handled the request, closing its circuit breaker if it was open.
+	pub fn success(&self) {
+		let up = &self.upstream;
+		self.recorded.store(true, Ordering::SeqCst);
+		if up.breaker.is_none() {
+			return
+		}
+
+		up.failures.store(0, Ordering::SeqCst);
+		let mut opened = up.opened.lock().unwrap_or_else(|e| e.into_inner());
+		if opened.take().is_some() {
+			up.trial.store(false, Ordering::SeqCst);
+			println!("[Info]: Upstream {} has recovered, and has been put back into rotation.", up.dest);
+		}
+	}
+
+	// Record that the upstream failed to handle the request, opening its circuit breaker if it has failed too many times in a row.
+	pub fn failure(&self) {
+		let up = &self.upstream;
+		self.recorded.store(true, Ordering::SeqCst);
+		let breaker = match up.breaker {
+			Some(b) => b,
+			None => return,
+		};
+
+		let fails = up.failures.fetch_add(1, Ordering::SeqCst) + 1;
+		let mut opened = up.opened.lock().unwrap_or_else(|e| e.into_inner());
+		if opened.is_some() {
+			// A failed trial request re-opens the breaker for another fail_timeout.
+			*opened = Some(Instant::now());
+			up.trial.store(false, Ordering::SeqCst);
+		} else if fails >= breaker.max_fails {
+			*opened = Some(Instant::now());
+			println!("[Warn]: Upstream {} failed {} requests in a row, and has been taken out of rotation for {} seconds.", up.dest, fails, breaker.fail_timeout);
+		}
+	}
+}
+
+impl Drop for InFlight {
+	fn drop(&mut self) {
+		self.upstream.in_flight.fetch_sub(1, Ordering::SeqCst);
+
+		// Requests can be dropped without an outcome, such as when the client disconnects. If this was a trial request, allow another one so the upstream doesn't stay out of rotation.
+		if self.trial && !self.recorded.load(Ordering::SeqCst) {
+			self.upstream.trial.store(false, Ordering::SeqCst);
+		}
+	}
+}
+
+// A group of upstream servers which a proxy location can send requests to.
+pub struct Pool {
+	strategy: Strategy,
+	upstreams: Vec<Arc<Upstream>>,
+	counter: AtomicUsize,
+	health: Option<HealthCheck>,
+}
+
+impl Pool {
+	// Create a pool from a list of destinations and their weights. Weights lower than 1 are treated as 1.
+	pub fn new(strategy: Strategy, upstreams: Vec<(String, usize)>, health: Option<HealthCheck>, breaker: Option<Breaker>) -> Self {
+		Self {
+			strategy,
+			upstreams: upstreams.into_iter().map(|(dest, weight)| Arc::new(Upstream {
+				dest,
+				weight: if weight < 1 {1} else {weight},
+				in_flight: AtomicUsize::new(0),
+				healthy: AtomicBool::new(true),
+				breaker,
+				failures: AtomicUsize::new(0),
+				opened: Mutex::new(None),
+				trial: AtomicBool::new(false),
+			})).collect(),
+			counter: AtomicUsize::new(0),
+			health,
+		}
+	}
+
+	/* Copy the health of upstream servers from the pool a config reload replaced, so that upstreams which were failing health checks don't go back into rotation.
+	Only upstreams with the same destination are copied, and only if both pools check the same path for the same status. */
+	pub fn inherit_health(&self, old: &Self) {
+		match (self.health.as_ref(), old.health.as_ref()) {
+			(Some(new), Some(prev)) if new.path == prev.path && new.status == prev.status => (),
+			_ => return,
+		}
+
+		for upstream in &self.upstreams {
+			if let Some(prev) = old.upstreams.iter().find(|u| u.dest == upstream.dest) {
+				upstream.healthy.store(prev.healthy.load(Ordering::Relaxed), Ordering::Relaxed);
+			}
+		}
+	}
+
+	// Get the destinations of every upstream server in the pool.
+	pub fn dests(&self) -> Vec<&str> {
+		self.upstreams.iter().map(|u| u.dest.as_str()).collect()
+	}
+
+	// Pick a healthy upstream server for a request from the specified client. None is returned if the pool has no healthy upstream servers.
+	pub fn pick(&self, client_ip: &str) -> Option<InFlight> {
+		self.pick_excluding(client_ip, &[])
+	}
+
+	// Pick a healthy upstream server, skipping the specified destinations. This is used to pick a different upstream server when a request is retried.
+	pub fn pick_excluding(&self, client_ip: &str, exclude: &[String]) -> Option<InFlight> {
+		let upstreams: Vec<&Arc<Upstream>> = self.upstreams.iter().filter(|u| u.healthy.load(Ordering::Relaxed) && u.available() && !exclude.contains(&u.dest)).collect();
+		if upstreams.is_empty() {
+			return None
+		}
+
+		let upstream = match self.strategy {
+			Strategy::RoundRobin => upstreams[self.counter.fetch_add(1, Ordering::Relaxed) % upstreams.len()],
+			Strategy::Weighted => {
+				let total: usize = upstreams.iter().map(|u| u.weight).sum();
+				let mut n = self.counter.fetch_add(1, Ordering::Relaxed) % total;
+				let mut chosen = upstreams[0];
+				for u in upstreams {
+					if n < u.weight {
+						chosen = u;
+						break
+					}
+					n -= u.weight;
+				}
+				chosen
+			},
+			Strategy::LeastInFlight => {
+				// Start searching from a rotating offset, so that idle upstreams share requests evenly instead of the first one getting all of them.
+				let offset = self.counter.fetch_add(1, Ordering::Relaxed);
+				let len = upstreams.len();
+				let mut chosen = upstreams[offset % len];
+				for i in 1..len {
+					let u = upstreams[(offset + i) % len];
+					if u.in_flight.load(Ordering::Relaxed) < chosen.in_flight.load(Ordering::Relaxed) {
+						chosen = u;
+					}
+				}
+				chosen
+			},
+			Strategy::IpHash => {
+				let mut hasher = DefaultHasher::new();
+				client_ip.hash(&mut hasher);
+				upstreams[(hasher.finish() % upstreams.len() as u64) as usize]
+			},
+		};
+
+		// If the upstream's breaker is half-open, this request is its trial request. If another request claimed the trial after the upstream was checked, a different upstream is picked instead.
+		let trial = upstream.opened.lock().unwrap_or_else(|e| e.into_inner()).is_some();
+		if trial && upstream.trial.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+			let mut exclude = exclude.to_vec();
+			exclude.push(upstream.dest.to_owned());
+			return self.pick_excluding(client_ip, &exclude)
+		}
+
+		Some(InFlight::new(upstream, trial))
+	}
+}
+
+/* Start actively checking the health of a pool's upstream servers, if health checks are enabled for it.
+The checker only holds a weak reference to the pool, so it stops once the pool is dropped after a config reload. */
+pub fn spawn_health_checks(pool: &Arc<Pool>) {
+	let check = match pool.health.to_owned() {
+		Some(c) => c,
+		None => return,
+	};
+
+	let weak = Arc::downgrade(pool);
+	let mut streaks = vec![0; pool.upstreams.len()];
+	thread::spawn(move || {
+		loop {
+			thread::sleep(Duration::from_secs(check.interval));
+			let pool = match weak.upgrade() {
+				Some(p) => p,
+				None => return,
+			};
+
+			check_pool(&pool, &check, &mut streaks);
+		}
+	});
+}
+
+/* Run one round of health checks on a pool, changing the health of upstream servers which have passed or failed enough checks in a row.
+streaks counts consecutive results which disagree with each upstream's current state. */
+fn check_pool(pool: &Pool, check: &HealthCheck, streaks: &mut [usize]) {
+	for (i, upstream) in pool.upstreams.iter().enumerate() {
+		let ok = probe(&upstream.dest, check);
+		let healthy = upstream.healthy.load(Ordering::Relaxed);
+
+		if ok == healthy {
+			streaks[i] = 0;
+			continue
+		}
+		streaks[i] += 1;
+
+		if healthy && streaks[i] >= check.fall {
+			upstream.healthy.store(false, Ordering::Relaxed);
+			streaks[i] = 0;
+			println!("[Warn]: Upstream {} failed {} health checks, and has been taken out of rotation.", upstream.dest, check.fall);
+		} else if !healthy && streaks[i] >= check.rise {
+			upstream.healthy.store(true, Ordering::Relaxed);
+			streaks[i] = 0;
+			println!("[Info]: Upstream {} passed {} health checks, and has been put back into rotation.", upstream.dest, check.rise);
+		}
+	}
+}
+
+/* Send a health check request to an upstream server, returning true if it responds with the expected status.
+FastCGI upstreams are only checked for whether they accept connections. */
+pub fn probe(dest: &str, check: &HealthCheck) -> bool {
+	let timeout = Duration::from_secs(check.interval);
+
+	// FastCGI application servers don't speak HTTP, so they are only checked for whether they accept connections.
+	if let Some(addr) = fastcgi_addr(dest) {
+		return cgi::connect(addr, timeout).is_ok()
+	}
+
+	#[cfg(unix)] {
+		if let Some((socket, prefix)) = unix_socket(dest) {
+			return match StdUnixStream::connect(socket) {
+				Ok(conn) => {
+					let _ = conn.set_read_timeout(Some(timeout));
+					let _ = conn.set_write_timeout(Some(timeout));
+					probe_http(conn, prefix, "localhost", check).unwrap_or(false)
+				},
+				Err(_) => false,
+			}
+		}
+	}
+
+	let uri: Uri = match dest.parse() {
+		Ok(u) => u,
+		Err(_) => return false,
+	};
+	let host = uri.host().unwrap_or("localhost");
+	let https = uri.scheme_str() == Some("https");
+	let port = uri.port_u16().unwrap_or(if https {443} else {80});
+
+	let addr = match (host.trim_start_matches('[').trim_end_matches(']'), port).to_socket_addrs().ok().and_then(|mut a| a.next()) {
+		Some(a) => a,
+		None => return false,
+	};
+	let conn = match TcpStream::connect_timeout(&addr, timeout) {
+		Ok(c) => c,
+		Err(_) => return false,
+	};
+	let _ = conn.set_read_timeout(Some(timeout));
+	let _ = conn.set_write_timeout(Some(timeout));
+
+	// HTTPS upstreams are checked over TLS, using the proxy's server name override if it has one. Upstreams specified by their IP address need a server name to be checked.
+	if https {
+		let name = check.tls_name.as_ref().map_or(host, String::as_str);
+		let dns_name = match DNSNameRef::try_from_ascii_str(name) {
+			Ok(n) => n,
+			Err(_) => return false,
+		};
+		let mut session = ClientSession::new(&check.tls, dns_name);
+		let mut conn = conn;
+		return probe_http(rustls::Stream::new(&mut session, &mut conn), uri.path().trim_end_matches('/'), host, check).unwrap_or(false)
+	}
+
+	probe_http(conn, uri.path().trim_end_matches('/'), host, check).unwrap_or(false)
+}
+
+// Send a health check request over an open connection, and check the status of the response.
+fn probe_http<S: Read + Write>(mut conn: S, prefix: &str, host: &str, check: &HealthCheck) -> io::Result<bool> {
+	let req = ["GET ", prefix, &check.path, " HTTP/1.0\r\nHost: ", host, "\r\nUser-Agent: KatWebX-Health\r\nConnection: close\r\n\r\n"].concat();
+	conn.write_all(req.as_bytes())?;
+
+	// Only the status code is needed, so there's no need to read the rest of the response.
+	let mut buf = [0; 64];
+	let n = conn.read(&mut buf)?;
+	let line = String::from_utf8_lossy(&buf[..n]);
+	Ok(line.split(' ').nth(1).and_then(|s| s.parse::<u16>().ok()) == Some(check.status))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{check_pool, probe, Breaker, HealthCheck, Pool, Strategy};
+	use rustls::ClientConfig;
+	use std::{io::{Read, Write}, net::TcpListener, sync::{Arc, Barrier, atomic::{AtomicUsize, Ordering}}, thread};
+
+	fn pool(dests: &[&str]) -> Pool {
+		let upstreams = dests.iter().map(|d| ((*d).to_owned(), 1)).collect();
+		Pool::new(Strategy::RoundRobin, upstreams, None, Some(Breaker {max_fails: 1, fail_timeout: 0}))
+	}
+
+	#[test]
+	fn round_robin() {
+		let pool = pool(&["http://a", "http://b"]);
+		let picked: Vec<String> = (0..4).filter_map(|_| pool.pick("127.0.0.1")).map(|u| u.dest().to_owned()).collect();
+		assert_eq!(picked, vec!["http://a", "http://b", "http://a", "http://b"]);
+		assert_eq!(pool.pick_excluding("127.0.0.1", &["http://a".to_owned()]).map(|u| u.dest().to_owned()), Some("http://b".to_owned()));
+	}
+
+	#[test]
+	fn half_open_recovery() {
+		let pool = pool(&["http://a"]);
+		pool.pick("127.0.0.1").expect("upstream should be available").failure();
+
+		// The breaker is half-open, so only a single trial request is let through.
+		let trial = pool.pick("127.0.0.1").expect("trial request should be allowed");
+		assert!(pool.pick("127.0.0.1").is_none());
+
+		// Dropping the trial request without an outcome allows another one.
+		drop(trial);
+		let trial = pool.pick("127.0.0.1").expect("trial request should be allowed after the last one was dropped");
+		trial.failure();
+		drop(trial);
+
+		// A successful trial request closes the breaker.
+		let trial = pool.pick("127.0.0.1").expect("trial request should be allowed after a failed one");
+		trial.success();
+		let first = pool.pick("127.0.0.1");
+		let second = pool.pick("127.0.0.1");
+		assert!(first.is_some() && second.is_some());
+	}
+
+	#[test]
+	fn concurrent_trial() {
+		let pool = Arc::new(pool(&["http://a"]));
+		pool.pick("127.0.0.1").expect("upstream should be available").failure();
+
+		// Requests picking the half-open upstream at the same time can't all become its trial request.
+		let barrier = Arc::new(Barrier::new(8));
+		let threads: Vec<_> = (0..8).map(|_| {
+			let (pool, barrier) = (pool.to_owned(), barrier.to_owned());
+			thread::spawn(move || {
+				barrier.wait();
+				let picked = pool.pick("127.0.0.1");
+				barrier.wait();
+				picked.is_some()
+			})
+		}).collect();
+		let trials = threads.into_iter().map(|t| t.join().expect("thread should finish")).filter(|&p| p).count();
+		assert_eq!(trials, 1);
+	}
+
+	// Start a stub upstream server, which answers requests for /health with the status in the returned counter, and anything else with a 404.
+	fn stub_server() -> (String, Arc<AtomicUsize>) {
+		let listener = TcpListener::bind("127.0.0.1:0").expect("stub server should start");
+		let addr = listener.local_addr().expect("stub server should have an address");
+		let status = Arc::new(AtomicUsize::new(200));
+		let response = status.to_owned();
+		thread::spawn(move || {
+			for conn in listener.incoming() {
+				let mut conn = match conn {
+					Ok(c) => c,
+					Err(_) => continue,
+				};
+				let mut buf = [0; 1024];
+				let n = conn.read(&mut buf).unwrap_or(0);
+				let status = if buf[..n].starts_with(b"GET /health HTTP/1.0\r\n") {response.load(Ordering::SeqCst)} else {404};
+				let _ = conn.write_all(["HTTP/1.0 ", &status.to_string(), " Stub\r\nContent-Length: 0\r\n\r\n"].concat().as_bytes());
+			}
+		});
+		(["http://", &addr.to_string()].concat(), status)
+	}
+
+	fn health_check() -> HealthCheck {
+		HealthCheck {
+			path: "/health".to_owned(),
+			interval: 1,
+			status: 200,
+			rise: 2,
+			fall: 3,
+			tls: Arc::new(ClientConfig::new()),
+			tls_name: None,
+		}
+	}
+
+	#[test]
+	fn probe_status() {
+		let (dest, status) = stub_server();
+		let check = health_check();
+		assert!(probe(&dest, &check));
+
+		status.store(503, Ordering::SeqCst);
+		assert!(!probe(&dest, &check));
+		assert!(!probe(&dest, &HealthCheck { path: "/missing".to_owned(), ..health_check() }));
+
+		// Nothing listens on a port once its listener has been dropped.
+		let closed = TcpListener::bind("127.0.0.1:0").and_then(|l| l.local_addr()).expect("port should be reserved");
+		assert!(!probe(&["http://", &closed.to_string()].concat(), &check));
+	}
+
+	#[test]
+	fn rise_and_fall() {
+		let (dest, status) = stub_server();
+		let check = health_check();
+		let pool = Pool::new(Strategy::RoundRobin, vec![(dest, 1)], Some(check.to_owned()), None);
+		let mut streaks = vec![0];
+
+		// The upstream is only taken out of rotation once it fails fall checks in a row.
+		status.store(500, Ordering::SeqCst);
+		for _ in 0..check.fall - 1 {
+			check_pool(&pool, &check, &mut streaks);
+			assert!(pool.pick("127.0.0.1").is_some());
+		}
+		check_pool(&pool, &check, &mut streaks);
+		assert!(pool.pick("127.0.0.1").is_none());
+
+		// A passing check resets the streak, so it has to pass rise checks in a row to be put back into rotation.
+		status.store(200, Ordering::SeqCst);
+		check_pool(&pool, &check, &mut streaks);
+		status.store(500, Ordering::SeqCst);
+		check_pool(&pool, &check, &mut streaks);
+		status.store(200, Ordering::SeqCst);
+		for _ in 0..check.rise - 1 {
+			check_pool(&pool, &check, &mut streaks);
+			assert!(pool.pick("127.0.0.1").is_none());
+		}
+		check_pool(&pool, &check, &mut streaks);
+		assert!(pool.pick("127.0.0.1").is_some());
+	}
+}
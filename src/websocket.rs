@@ -0,0 +1,180 @@
+// Websocket.rs handles reverse proxying websocket connections.
+extern crate actix;
+extern crate actix_codec;
+extern crate actix_web;
+extern crate actix_http;
+extern crate futures;
+extern crate bytes;
+
+use futures::{Async, Future, Poll, Sink, Stream, future};
+use bytes::{Bytes, BytesMut};
+use std::{io, rc::Rc, cell::Cell, time::{Duration, Instant}};
+use self::actix::{Arbiter, clock::Delay};
+use self::actix_codec::{Decoder, Encoder};
+use self::actix_http::ws::{self, Codec, Frame, Message, ProtocolError};
+use actix_web::{client::{Client, WsClientError}, dev::RequestHead, http::{header, header::HeaderMap, StatusCode}, error::PayloadError, web::Payload, HttpResponse, Error};
+use config::Config;
+use upstream::InFlight;
+use {proxy_error, ui};
+
+// The largest websocket frame that will be proxied, in bytes. This is much larger than actix's default, as the proxy shouldn't be deciding how large messages can be.
+const MAX_FRAME_SIZE: usize = 16_777_216;
+
+// Check if a request is asking to be upgraded to a websocket connection.
+pub fn is_upgrade(headers: &HeaderMap) -> bool {
+	if let Some(h) = headers.get(header::UPGRADE) {
+		return h.to_str().unwrap_or("").eq_ignore_ascii_case("websocket")
+	}
+	false
+}
+
+/* Reverse proxy a websocket connection. The handshake is completed with the upstream server before it is completed with the client.
+Frames are decoded and re-encoded in both directions, and the connection is closed if no ping/pong frames are seen within the timeout. */
+pub fn proxy(client: &Client, path: &str, head: &RequestHead, body: Payload, mut headers: HeaderMap, upstream: InFlight, c: &Config) -> Box<Future<Item=HttpResponse, Error=Error>> {
+	let (smaller, debug) = (c.smaller_default, c.proxy_debug);
+	let mut res = match ws::handshake(head) {
+		Ok(r) => r,
+		Err(_) => return Box::new(future::ok(ui::http_error(StatusCode::BAD_REQUEST, "400 Bad Request", "The server was unable to understand the websocket handshake.", smaller))),
+	};
+
+	// The client's handshake headers are replaced by the ones awc generates for the upstream handshake.
+	for key in &["sec-websocket-key", "sec-websocket-version", "sec-websocket-extensions"] {
+		headers.remove(*key);
+	}
+	let mut req = client.ws(path).max_frame_size(MAX_FRAME_SIZE);
+	for (key, value) in headers.iter() {
+		req = req.header(key.to_owned(), value.to_owned());
+	}
+
+	// Errors sending the handshake are reported the same way as other proxied requests, while errors in the upstream's handshake response are always a 502.
+	let (timeout, path) = (Duration::from_secs(c.websocket_timeout as u64), path.to_owned());
+	Box::new(req.connect().then(move |result| match result {
+		Err(err) => {
+			upstream.failure();
+			Err(match err {
+				WsClientError::SendRequest(e) => Error::from(proxy_error(&e, &path, smaller, debug)),
+				e => {
+					println!("[Warn]: Websocket connection to {} failed: {}", path, e);
+					Error::from(ui::http_error(StatusCode::BAD_GATEWAY, "502 Bad Gateway", "The server was acting as a proxy and was unable to open a websocket connection to the upstream server.", smaller))
+				},
+			})
+		},
+		Ok((resp, framed)) => {
+			upstream.success();
+			if let Some(proto) = resp.headers().get(header::SEC_WEBSOCKET_PROTOCOL) {
+				res.header(header::SEC_WEBSOCKET_PROTOCOL, proto.to_owned());
+			}
+
+			// Both directions share the time of the last ping/pong frame, so that a ping in one direction and a pong in the other keeps the connection alive.
+			let last = Rc::new(Cell::new(Instant::now()));
+			let (sink, stream) = framed.split();
+
+			let client = IdleTimeout::new(FrameDecoder::new(body), last.to_owned(), timeout).map(frame_to_message);
+			Arbiter::spawn(sink.send_all(client).map(|_| ()).map_err(|_| ()));
+
+			// The upstream's in-flight guard is owned by the response stream, so the connection counts against the upstream until it is closed.
+			let mut codec = Codec::new().max_size(MAX_FRAME_SIZE);
+			Ok(res.header(header::SERVER, "KatWebX")
+				.streaming(IdleTimeout::new(stream, last, timeout).map(move |frame| {
+					let _ = &upstream;
+					let mut buf = BytesMut::new();
+					let _ = codec.encode(frame_to_message(frame), &mut buf);
+					buf.freeze()
+				})))
+		},
+	}))
+}
+
+// Turn a received frame into a message that can be sent to the other side of the connection.
+fn frame_to_message(frame: Frame) -> Message {
+	match frame {
+		Frame::Text(t) => Message::Text(t.map_or_else(String::new, |t| String::from_utf8_lossy(&t).into_owned())),
+		Frame::Binary(b) => Message::Binary(b.map_or_else(Bytes::new, BytesMut::freeze)),
+		Frame::Ping(p) => Message::Ping(p),
+		Frame::Pong(p) => Message::Pong(p),
+		Frame::Close(r) => Message::Close(r),
+	}
+}
+
+// FrameDecoder turns the raw request payload of an upgraded connection into websocket frames.
+struct FrameDecoder {
+	payload: Payload,
+	codec: Codec,
+	buf: BytesMut,
+	eof: bool,
+}
+
+impl FrameDecoder {
+	fn new(payload: Payload) -> Self {
+		Self { payload, codec: Codec::new().max_size(MAX_FRAME_SIZE), buf: BytesMut::new(), eof: false }
+	}
+}
+
+impl Stream for FrameDecoder {
+	type Item = Frame;
+	type Error = ProtocolError;
+
+	fn poll(&mut self) -> Poll<Option<Frame>, ProtocolError> {
+		loop {
+			if let Some(frame) = self.codec.decode(&mut self.buf)? {
+				return Ok(Async::Ready(Some(frame)))
+			}
+
+			if self.eof {
+				return Ok(Async::Ready(None))
+			}
+
+			match self.payload.poll() {
+				Ok(Async::Ready(Some(chunk))) => self.buf.extend_from_slice(&chunk),
+				Ok(Async::Ready(None)) => self.eof = true,
+				Ok(Async::NotReady) => return Ok(Async::NotReady),
+				Err(PayloadError::Io(err)) => return Err(ProtocolError::Io(err)),
+				Err(_) => return Err(ProtocolError::Io(io::Error::new(io::ErrorKind::Other, "Unable to read websocket payload"))),
+			}
+		}
+	}
+}
+
+// IdleTimeout ends a stream of frames once no ping/pong frames have been seen for the specified duration.
+struct IdleTimeout<S> {
+	stream: S,
+	last: Rc<Cell<Instant>>,
+	delay: Delay,
+	timeout: Duration,
+}
+
+impl<S> IdleTimeout<S> {
+	fn new(stream: S, last: Rc<Cell<Instant>>, timeout: Duration) -> Self {
+		let deadline = last.get() + timeout;
+		Self { stream, last, delay: Delay::new(deadline), timeout }
+	}
+}
+
+impl<S: Stream<Item=Frame>> Stream for IdleTimeout<S> {
+	type Item = Frame;
+	type Error = S::Error;
+
+	fn poll(&mut self) -> Poll<Option<Frame>, S::Error> {
+		match self.stream.poll()? {
+			Async::Ready(Some(frame)) => {
+				match frame {
+					Frame::Ping(_) | Frame::Pong(_) => self.last.set(Instant::now()),
+					_ => (),
+				}
+				return Ok(Async::Ready(Some(frame)))
+			},
+			Async::Ready(None) => return Ok(Async::Ready(None)),
+			Async::NotReady => (),
+		}
+
+		let deadline = self.last.get() + self.timeout;
+		if deadline > Instant::now() {
+			self.delay.reset(deadline);
+			if let Ok(Async::NotReady) = self.delay.poll() {
+				return Ok(Async::NotReady)
+			}
+		}
+
+		Ok(Async::Ready(None))
+	}
+}